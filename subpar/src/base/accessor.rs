@@ -5,7 +5,7 @@
 
 use crate::local::*;
 // use anyhow::Context;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub enum Accessor {
@@ -34,9 +34,33 @@ impl Accessor {
   /// URLs are simply filled in (if known)
   /// Files require the existence of a directory. If create_missing is true, they will be created
   /// on the local file system but will not create the file itself.
-  pub fn canonicalize(self, _create_missing: bool) -> Result<Accessor> {
+  pub fn canonicalize(self, create_missing: bool) -> Result<Accessor> {
     match self {
-      Accessor::Csv(path) => Ok(Accessor::Csv(helpers::canonicalize(path)?)),
+      Accessor::Csv(path) => {
+        if create_missing && !path.exists() {
+          // A file that is about to be written only needs its directory to exist
+          let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+          };
+          err_into!(
+            std::fs::create_dir_all(parent),
+            "Could not create the directory for '{}'",
+            path.to_string_lossy()
+          )?;
+          let file_name = path.file_name().ok_or_else(|| {
+            err!(
+              InvalidPath,
+              "path {} does not name a file",
+              path.to_string_lossy()
+            )
+          })?;
+          return Ok(Accessor::Csv(
+            helpers::canonicalize(parent.to_path_buf())?.join(file_name),
+          ));
+        }
+        Ok(Accessor::Csv(helpers::canonicalize(path)?))
+      }
     }
   }
 
@@ -2,14 +2,19 @@
 //!
 //! This is a wrapped value, designed to hold the data in intermediate form
 
+use crate::base::options::CellOptions;
 use crate::local::*;
 
 use std::convert::TryFrom;
 
+use chrono::{Duration, NaiveDate, NaiveDateTime, TimeZone};
 use schemars::schema::*;
 use serde_json::Number;
 use serde_json::Value as JsonValue;
 
+/// The pattern schemars gives decimal types, which are serialized as strings to stay exact
+pub const DECIMAL_PATTERN: &str = r"^-?[0-9]+(\.[0-9]+)?$";
+
 /// Annotate cells, so we can also add custom deserialization
 /// TODO: Change Serialize/Deserialize into From/Into traits
 pub trait SubparCell: Serialize + serde::de::DeserializeOwned + Clone + Send + Sync {}
@@ -30,21 +35,93 @@ pub enum CellValue {
   Number(Number),
   /// The default string type
   String(String),
+  /// A true/false value typed by the sheet
+  Bool(bool),
+  /// A date and time without a timezone, as spreadsheets store them
+  DateTime(NaiveDateTime),
+  /// A calendar date
+  Date(NaiveDate),
+  /// A length of time, such as an Excel or ODS duration
+  Duration(Duration),
+  /// A calculated cell, read by the value the sheet last calculated for it
+  Formula {
+    expr: String,
+    cached: Box<CellValue>,
+  },
+  /// The sheet's own error for the cell, such as "#DIV/0!"
+  Error(String),
+  /// Linked text, like a Sheets HYPERLINK cell
+  Hyperlink { text: String, url: String },
 }
 
 impl CellValue {
-  /// Change the cell into the expected Json type
-  fn convert(&self, i_type: Option<&InstanceType>) -> Result<JsonValue> {
-    // pub enum InstanceType {
-    //   Null,
-    //   Boolean,
-    //   Object,
-    //   Array,
-    //   Number,
-    //   String,
-    //   Integer,
+  /// Whether the reader found nothing in the cell
+  pub fn is_empty(&self) -> bool {
+    match self {
+      CellValue::Null | CellValue::Empty => true,
+      CellValue::Formula { cached, .. } => cached.is_empty(),
+      _ => false,
+    }
+  }
 
+  /// Parse the value into serde_json value using the schema, validating it as needed
+  pub fn to_value(&self, schema: &SchemaObject, opts: &CellOptions) -> Result<JsonValue> {
+    match &schema.instance_type {
+      Some(SingleOrVec::Vec(i_types)) => {
+        // A blank cell in a nullable column is a null, rather than whatever the other types make of it
+        if self.is_empty() && i_types.contains(&InstanceType::Null) {
+          return Ok(JsonValue::Null);
+        }
+
+        for i_type in i_types {
+          if let Ok(val) = self.convert(Some(i_type), schema, opts) {
+            return Ok(val);
+          };
+        }
+
+        // The messages are only built once every type has failed, since this runs for every cell
+        let msg = format!(
+          "Could not convert cell {:?} into any of {:?}",
+          self, i_types
+        );
+        log::debug!("{}", msg);
+        Err(err!(
+          NotFound,
+          "Could not find a valid type to convert the cell into: {:#?}",
+          self
+        ))
+        .context(msg)
+      }
+      Some(SingleOrVec::Single(i_type)) => self.convert(Some(i_type), schema, opts),
+      None => self.convert(None, schema, opts),
+    }
+  }
+
+  /// Change the cell into the expected Json type
+  fn convert(
+    &self,
+    i_type: Option<&InstanceType>,
+    schema: &SchemaObject,
+    opts: &CellOptions,
+  ) -> Result<JsonValue> {
     log::trace!("Trying to convert {:?}: {:?}", i_type, self);
+    match self {
+      CellValue::Formula { cached, .. } => return cached.convert(i_type, schema, opts),
+      CellValue::Error(msg) => {
+        return Err(err!(
+          BadValue,
+          "The sheet reported an error in the cell: {}",
+          msg
+        ))
+      }
+      // Only strings and objects care about the link, everything else reads the text
+      CellValue::Hyperlink { text, .. } => match i_type {
+        Some(InstanceType::String) | Some(InstanceType::Object) => (),
+        _ => return CellValue::Raw(text.clone()).convert(i_type, schema, opts),
+      },
+      _ => (),
+    }
+
     match i_type {
       Some(InstanceType::Null) => match self {
         CellValue::Null | CellValue::Empty => Ok(JsonValue::Null),
@@ -53,30 +130,68 @@ impl CellValue {
           "Cannot reasonably convert a value into a null. Try again"
         )),
       },
+      Some(InstanceType::Boolean) => {
+        let text = match self {
+          CellValue::Bool(value) => return Ok(JsonValue::Bool(*value)),
+          CellValue::String(val) | CellValue::Raw(val) => val.clone(),
+          CellValue::Null | CellValue::Empty => String::new(),
+          x => x.to_string(),
+        };
+        match opts.parse_bool(&text) {
+          Some(value) => Ok(JsonValue::Bool(value)),
+          None => Err(err!(
+            ConversionError,
+            "'{}' is not one of the true values {:?} or false values {:?}",
+            text,
+            opts.truthy,
+            opts.falsy
+          )),
+        }
+      }
       Some(InstanceType::Number) => match self {
         CellValue::String(val) | CellValue::Raw(val) => {
           use core::str::FromStr;
+          let number = opts.normalize_number(val)?;
           Ok(JsonValue::Number(err_into!(
-            serde_json::Number::from_str(val),
-            "Failed to convert {:?} into number",
-            val
+            serde_json::Number::from_str(&number),
+            "Failed to convert '{}' (read as '{}') into number",
+            val,
+            number
           )?))
         }
         CellValue::Number(num) => Ok(JsonValue::Number(num.clone())),
+        CellValue::Duration(duration) => Ok(JsonValue::Number(CellValue::seconds(duration))),
         _ => Err(err!(
           ConversionError,
-          "Cannot reasonably convert a value into a number. Try again"
+          "Cannot reasonably convert {:?} into a number",
+          self
         )),
       },
       Some(InstanceType::Integer) => match self {
         CellValue::String(val) | CellValue::Raw(val) => {
-          let int = err_into!(val.parse::<i64>())?;
-          Ok(JsonValue::Number(serde_json::Number::from(int)))
+          let number = opts.normalize_number(val)?;
+          CellValue::parse_integer(&number)
+            .map(JsonValue::Number)
+            .with_context(|| {
+              format!(
+                "Failed to convert '{}' (read as '{}') into an integer",
+                val, number
+              )
+            })
         }
         CellValue::Number(num) => Ok(JsonValue::Number(num.clone())),
+        CellValue::Duration(duration) => match CellValue::seconds(duration) {
+          num if num.is_f64() => Err(err!(
+            ConversionError,
+            "The duration {} is not a whole number of seconds",
+            duration
+          )),
+          num => Ok(JsonValue::Number(num)),
+        },
         _ => Err(err!(
           ConversionError,
-          "Cannot reasonably convert a value into a number. Try again"
+          "Cannot reasonably convert {:?} into an integer",
+          self
         )),
       },
       Some(InstanceType::String) => match self {
@@ -84,17 +199,351 @@ impl CellValue {
           BadValue,
           "Strings are not allowed to be null. Try again"
         )),
-        CellValue::Raw(val) | CellValue::String(val) => Ok(JsonValue::String(val.clone())),
+        CellValue::Raw(val) | CellValue::String(val) => {
+          // Decimals are kept as text so no precision is lost on the way to the decimal type
+          if CellValue::is_decimal(schema) {
+            return Ok(JsonValue::String(opts.normalize_number(val)?));
+          }
+
+          // Dates and times are rewritten into ISO 8601 so chrono can deserialize them
+          let date = match &schema.format {
+            Some(format) => opts.normalize_date(format, val)?,
+            None => None,
+          };
+          Ok(JsonValue::String(date.unwrap_or_else(|| val.clone())))
+        }
         CellValue::Number(num) => Ok(JsonValue::Number(num.clone())),
         CellValue::Empty => Ok(JsonValue::Null),
+        CellValue::DateTime(value) => Ok(JsonValue::String(
+          opts.write_datetime(schema.format.as_deref(), value),
+        )),
+        CellValue::Date(value) => match (schema.format.as_deref(), value.and_hms_opt(0, 0, 0)) {
+          (Some(format @ "date-time"), Some(midnight))
+          | (Some(format @ "partial-date-time"), Some(midnight)) => Ok(JsonValue::String(
+            opts.write_datetime(Some(format), &midnight),
+          )),
+          _ => Ok(JsonValue::String(value.format("%Y-%m-%d").to_string())),
+        },
+        CellValue::Hyperlink { text, url } => match schema.format.as_deref() {
+          Some("uri") => Ok(JsonValue::String(url.clone())),
+          _ => Ok(JsonValue::String(text.clone())),
+        },
+        x => Ok(JsonValue::String(x.to_string())),
+      },
+      Some(InstanceType::Array) => {
+        let text = match self {
+          CellValue::String(val) | CellValue::Raw(val) => val.as_str(),
+          CellValue::Null | CellValue::Empty => "",
+          _ => return CellValue::Raw(self.to_string()).convert(i_type, schema, opts),
+        };
+
+        // Each item is converted by the array's item schema, if it has a single one
+        let items = match schema.array.as_ref().and_then(|x| x.items.as_ref()) {
+          Some(SingleOrVec::Single(item)) => match &**item {
+            Schema::Object(item) => Some(item),
+            Schema::Bool(_) => None,
+          },
+          _ => None,
+        };
+
+        let mut values = Vec::new();
+        for (i, item) in opts.split_items(text).into_iter().enumerate() {
+          let cell = CellValue::Raw(item.to_string());
+          let value = match items {
+            Some(item_schema) => cell.to_value(item_schema, opts),
+            None => cell.convert(None, schema, opts),
+          };
+          values.push(value.map_err(|err| {
+            err!(
+              ConversionError,
+              "Could not convert item {} ('{}') of array '{}': {:?}",
+              i,
+              item,
+              text,
+              err
+            )
+          })?);
+        }
+        Ok(JsonValue::Array(values))
+      }
+      Some(InstanceType::Object) => match self {
+        CellValue::String(val) | CellValue::Raw(val) => {
+          match serde_json::from_str::<JsonValue>(val) {
+            Ok(value @ JsonValue::Object(_)) => Ok(value),
+            Ok(value) => Err(err!(
+              ConversionError,
+              "Expected a JSON object in the cell, but found {}",
+              value
+            )),
+            Err(err) => Err(err!(
+              ConversionError,
+              "Could not parse the cell as a JSON object: {}",
+              err
+            )),
+          }
+        }
+        CellValue::Hyperlink { text, url } => Ok(serde_json::json!({ "text": text, "url": url })),
+        _ => Err(err!(
+          ConversionError,
+          "Cannot reasonably convert {:?} into an object",
+          self
+        )),
       },
       // None just returns what serde_json guessed
       None => match self {
         CellValue::Raw(val) | CellValue::String(val) => Ok(JsonValue::String(val.clone())),
         CellValue::Number(num) => Ok(JsonValue::Number(num.clone())),
         CellValue::Null | CellValue::Empty => Ok(JsonValue::Null),
+        CellValue::Bool(value) => Ok(JsonValue::Bool(*value)),
+        x => Ok(JsonValue::String(x.to_string())),
+      },
+    }
+  }
+}
+
+impl CellValue {
+  /// Parse integer text, falling back to the exact text for values past 64 bits
+  fn parse_integer(number: &str) -> Result<Number> {
+    if let Ok(int) = number.parse::<i64>() {
+      return Ok(Number::from(int));
+    }
+    if let Ok(int) = number.parse::<u64>() {
+      return Ok(Number::from(int));
+    }
+
+    if number.parse::<i128>().is_err() && number.parse::<u128>().is_err() {
+      return Err(err!(
+        ConversionError,
+        "'{}' is not a whole number that fits in 128 bits",
+        number
+      ));
+    }
+
+    match cfg!(feature = "decimal") {
+      // Arbitrary precision numbers keep the digits, so i128/u128 fields deserialize exactly
+      true => {
+        use core::str::FromStr;
+        err_into!(Number::from_str(number))
+      }
+      false => Err(err!(
+        ConversionError,
+        "{} is too large for a JSON number. Enable the 'decimal' feature to read 128 bit integers",
+        number
+      )),
+    }
+  }
+
+  /// A duration as a number of seconds, keeping any fraction
+  fn seconds(duration: &Duration) -> Number {
+    let millis = duration.num_milliseconds();
+    match millis % 1000 {
+      0 => Number::from(millis / 1000),
+      _ => Number::from_f64(millis as f64 / 1000.0).unwrap_or_else(|| Number::from(0)),
+    }
+  }
+
+  /// Whether the schema is a number written as a string, such as rust_decimal::Decimal
+  pub(crate) fn is_decimal(schema: &SchemaObject) -> bool {
+    match schema.string.as_ref().and_then(|x| x.pattern.as_ref()) {
+      Some(pattern) => pattern == DECIMAL_PATTERN,
+      None => false,
+    }
+  }
+}
+
+/// The codec shared by readers and writers
+///
+/// Decoding turns a cell into JSON without a schema, keeping only what the cell's own type says.
+/// Encoding goes the other way for writers, using the column's schema to recover the typed value
+/// from its JSON form and the column's options to spell it.
+impl CellValue {
+  /// Read the cell into JSON without a schema
+  pub fn decode(&self) -> Result<JsonValue> {
+    self.convert(None, &SchemaObject::default(), &CellOptions::default())
+  }
+
+  /// Turn a JSON value from a row back into a cell for the column described by the schema
+  pub fn encode(value: &JsonValue, schema: &SchemaObject, opts: &CellOptions) -> Result<CellValue> {
+    match value {
+      JsonValue::Null => Ok(CellValue::Empty),
+      JsonValue::Bool(value) => Ok(CellValue::Bool(*value)),
+      JsonValue::Number(num) => {
+        // Only number columns are rounded, so integers never grow a fraction
+        let is_number = match &schema.instance_type {
+          Some(SingleOrVec::Single(i_type)) => **i_type == InstanceType::Number,
+          Some(SingleOrVec::Vec(i_types)) => i_types.contains(&InstanceType::Number),
+          None => false,
+        };
+        match opts.decimal_places.filter(|_| is_number) {
+          Some(places) => match CellValue::round_number(num, places) {
+            Some(text) => Ok(CellValue::Raw(text)),
+            None => Ok(CellValue::Number(num.clone())),
+          },
+          None => Ok(CellValue::Number(num.clone())),
+        }
+      }
+      JsonValue::String(text) => match opts.decimal_places {
+        // Decimals are kept as strings so they stay exact, but they're still numbers to round
+        Some(places) if CellValue::is_decimal(schema) => {
+          match CellValue::round_text(text, places) {
+            Some(text) => Ok(CellValue::Raw(text)),
+            None => CellValue::encode_string(text, schema, opts),
+          }
+        }
+        _ => CellValue::encode_string(text, schema, opts),
       },
-      _ => unimplemented!("'Other conversions' still needs to be implemented"),
+      JsonValue::Array(items) => {
+        let item_schema = match schema.array.as_ref().and_then(|x| x.items.as_ref()) {
+          Some(SingleOrVec::Single(item)) => match &**item {
+            Schema::Object(item) => item.clone(),
+            Schema::Bool(_) => SchemaObject::default(),
+          },
+          _ => SchemaObject::default(),
+        };
+
+        let mut texts = Vec::new();
+        for (i, item) in items.iter().enumerate() {
+          let cell = CellValue::encode(item, &item_schema, opts)
+            .context(format!("Could not encode item {} of the array", i))?;
+          texts.push(match cell {
+            CellValue::Null | CellValue::Empty => String::new(),
+            cell => cell.to_text(opts),
+          });
+        }
+        Ok(CellValue::String(texts.join(&opts.delimiter)))
+      }
+      JsonValue::Object(_) => Ok(CellValue::String(value.to_string())),
+    }
+  }
+
+  /// Spell the number with a fixed number of decimal places, rounding halves away from zero
+  ///
+  /// The number's shortest text is rounded rather than the float under it, so 2.675 comes out as
+  /// 2.68 even though the nearest float is a little less. Only numbers in exponent form without
+  /// the decimal feature are rounded as floats.
+  fn round_number(num: &Number, places: usize) -> Option<String> {
+    CellValue::round_text(&num.to_string(), places)
+      .or_else(|| num.as_f64().map(|value| format!("{:.*}", places, value)))
+  }
+
+  /// Round number text to a fixed number of decimal places, halves away from zero
+  ///
+  /// With the decimal feature this also takes exponents and keeps 20 digit amounts exact.
+  /// Otherwise it only takes plain decimal text, like a Decimal stored as a string.
+  fn round_text(text: &str, places: usize) -> Option<String> {
+    #[cfg(feature = "decimal")]
+    {
+      use rust_decimal::{Decimal, RoundingStrategy};
+      use std::str::FromStr;
+
+      if let Ok(value) = Decimal::from_str(text).or_else(|_| Decimal::from_scientific(text)) {
+        let mut value =
+          value.round_dp_with_strategy(places as u32, RoundingStrategy::MidpointAwayFromZero);
+        value.rescale(places as u32);
+        return Some(value.to_string());
+      }
+    }
+
+    let (negative, digits) = match text.strip_prefix('-') {
+      Some(digits) => (true, digits),
+      None => (false, text),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let is_digits = |part: &str| part.bytes().all(|c| c.is_ascii_digit());
+    if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+      return None;
+    }
+
+    // The kept digits, padded out to the places, with one added for a dropped half or more
+    let mut kept: Vec<u8> = whole
+      .bytes()
+      .chain(fraction.bytes().chain(std::iter::repeat(b'0')).take(places))
+      .collect();
+    if matches!(fraction.as_bytes().get(places), Some(digit) if *digit >= b'5') {
+      let carried = kept.iter_mut().rev().all(|digit| match *digit {
+        b'9' => {
+          *digit = b'0';
+          true
+        }
+        _ => {
+          *digit += 1;
+          false
+        }
+      });
+      if carried {
+        kept.insert(0, b'1');
+      }
+    }
+
+    let (whole, fraction) = kept.split_at(kept.len() - places);
+    let mut rounded = String::with_capacity(kept.len() + 2);
+    if negative && kept.iter().any(|digit| *digit != b'0') {
+      rounded.push('-');
+    }
+    rounded.push_str(std::str::from_utf8(whole).ok()?);
+    if places > 0 {
+      rounded.push('.');
+      rounded.push_str(std::str::from_utf8(fraction).ok()?);
+    }
+    Some(rounded)
+  }
+
+  /// Strings that hold dates are parsed back into their typed values
+  fn encode_string(text: &str, schema: &SchemaObject, opts: &CellOptions) -> Result<CellValue> {
+    let cell = match schema.format.as_deref() {
+      Some("date") => CellValue::Date(err_into!(
+        NaiveDate::parse_from_str(text, "%Y-%m-%d"),
+        "Could not encode '{}' as a date",
+        text
+      )?),
+      Some("date-time") => {
+        let value = err_into!(
+          chrono::DateTime::parse_from_rfc3339(text),
+          "Could not encode '{}' as a date time",
+          text
+        )?;
+        CellValue::DateTime(value.with_timezone(&opts.timezone).naive_local())
+      }
+      Some("partial-date-time") => match text.parse::<NaiveDateTime>() {
+        Ok(value) => CellValue::DateTime(value),
+        // Times share the format, and are already spelled the way they were read
+        Err(_) => CellValue::String(text.to_string()),
+      },
+      _ => CellValue::String(text.to_string()),
+    };
+    Ok(cell)
+  }
+
+  /// Spell the cell as text using the column's output options
+  pub fn to_text(&self, opts: &CellOptions) -> String {
+    match self {
+      CellValue::Null | CellValue::Empty => opts.null_output.clone(),
+      CellValue::Bool(true) => opts.true_output.clone(),
+      CellValue::Bool(false) => opts.false_output.clone(),
+      CellValue::Date(value) => value.format(&opts.date_output).to_string(),
+      CellValue::DateTime(value) => match opts.timezone.from_local_datetime(value).single() {
+        Some(value) => value.format(&opts.datetime_output).to_string(),
+        None => value.format(&opts.datetime_output).to_string(),
+      },
+      CellValue::Formula { cached, .. } => cached.to_text(opts),
+      x => x.to_string(),
+    }
+  }
+}
+
+impl std::fmt::Display for CellValue {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CellValue::Null | CellValue::Empty => Ok(()),
+      CellValue::Raw(val) | CellValue::String(val) => write!(f, "{}", val),
+      CellValue::Number(num) => write!(f, "{}", num),
+      CellValue::Bool(value) => write!(f, "{}", value),
+      CellValue::DateTime(value) => write!(f, "{}", value.format("%Y-%m-%dT%H:%M:%S%.f")),
+      CellValue::Date(value) => write!(f, "{}", value.format("%Y-%m-%d")),
+      CellValue::Duration(value) => write!(f, "{}", value),
+      CellValue::Formula { cached, .. } => write!(f, "{}", cached),
+      CellValue::Error(msg) => write!(f, "{}", msg),
+      CellValue::Hyperlink { text, .. } => write!(f, "{}", text),
     }
   }
 }
@@ -114,33 +563,21 @@ impl Cell {
     self.name.clone()
   }
 
+  pub fn value(&self) -> &CellValue {
+    &self.value
+  }
+
+  /// Whether the reader found nothing in the cell
+  pub fn is_empty(&self) -> bool {
+    self.value.is_empty()
+  }
+
   // Parse the cell into serde_json value using the schema, validating it as needed
-  pub fn to_value(&self, schema: &SchemaObject) -> Result<JsonValue> {
-    match &schema.instance_type {
-      Some(SingleOrVec::Vec(i_types)) => {
-        let mut result = Err(err!(
-          NotFound,
-          "Could not find a valid type to convert the cell into: {:#?}",
-          self
-        ));
-        for i_type in i_types {
-          if let Ok(val) = self.value.convert(Some(i_type)) {
-            result = Ok(val);
-            break;
-          };
-        }
-        let msg = format!(
-          "Could not convert cell {:?} into any of {:?}",
-          self, i_types
-        );
-        if result.is_err() {
-          log::debug!("{}", msg);
-        };
-        result.context(msg)
-      }
-      Some(SingleOrVec::Single(i_type)) => self.value.convert(Some(i_type)),
-      None => self.value.convert(None),
-    }
+  pub fn to_value(&self, schema: &SchemaObject, opts: &CellOptions) -> Result<JsonValue> {
+    self
+      .value
+      .to_value(schema, opts)
+      .with_context(|| format!("Could not convert cell '{}'", self.name))
   }
 
   // /// Parse from an unknown string into an intermediate form.
@@ -159,21 +596,17 @@ impl Cell {
   // }
 }
 
-// Begin the deserializer for the schema
-// use serde::{de::DeserializeOwned, Deserialize};
-
 impl TryFrom<Cell> for JsonValue {
   type Error = SubparError;
 
-  fn try_from(_cell: Cell) -> Result<JsonValue> {
-    unimplemented!("'Cell.try_from' still needs to be implemented")
+  fn try_from(cell: Cell) -> Result<JsonValue> {
+    cell
+      .value
+      .decode()
+      .context(format!("Could not decode cell '{}'", cell.name))
   }
 }
 
-pub struct ToValue<'de> {
-  _input: &'de Cell,
-}
-
 // Bug with generics and TryFrom: https://github.com/rust-lang/rust/issues/50133
 // pub(crate) struct Wrapper<T>(T);
 
@@ -238,5 +671,96 @@ impl SubparCell for char {}
 
 impl SubparCell for String {}
 
+#[cfg(feature = "decimal")]
+impl SubparCell for rust_decimal::Decimal {}
+
 // impl<'de: 'a, 'a, T> SubparCell for &'a T where T: SubparCell {}
 impl<T> SubparCell for Vec<T> where T: SubparCell {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rounded(places: usize) -> CellOptions {
+    CellOptions {
+      decimal_places: Some(places),
+      ..Default::default()
+    }
+  }
+
+  fn encode(value: JsonValue, schema: &SchemaObject, places: usize) -> String {
+    CellValue::encode(&value, schema, &rounded(places))
+      .unwrap()
+      .to_text(&rounded(places))
+  }
+
+  fn number() -> SchemaObject {
+    SchemaObject {
+      instance_type: Some(InstanceType::Number.into()),
+      ..Default::default()
+    }
+  }
+
+  fn decimal() -> SchemaObject {
+    SchemaObject {
+      instance_type: Some(InstanceType::String.into()),
+      string: Some(Box::new(StringValidation {
+        pattern: Some(DECIMAL_PATTERN.to_string()),
+        ..Default::default()
+      })),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn numbers_round_half_away_from_zero() {
+    let number = number();
+    assert_eq!(encode(JsonValue::from(2.675), &number, 2), "2.68");
+    assert_eq!(encode(JsonValue::from(0.125), &number, 2), "0.13");
+    assert_eq!(encode(JsonValue::from(-2.5), &number, 0), "-3");
+    assert_eq!(encode(JsonValue::from(9.995), &number, 2), "10.00");
+    assert_eq!(encode(JsonValue::from(-0.001), &number, 2), "0.00");
+    assert_eq!(encode(JsonValue::from(3), &number, 2), "3.00");
+    assert_eq!(
+      encode(JsonValue::from(1e20), &number, 1),
+      "100000000000000000000.0"
+    );
+
+    // Integer columns are left alone
+    let integer = SchemaObject {
+      instance_type: Some(InstanceType::Integer.into()),
+      ..Default::default()
+    };
+    assert_eq!(encode(JsonValue::from(3), &integer, 2), "3");
+  }
+
+  #[test]
+  fn decimal_strings_are_rounded() {
+    let decimal = decimal();
+    assert_eq!(encode(JsonValue::from("1.005"), &decimal, 2), "1.01");
+    assert_eq!(encode(JsonValue::from("-7"), &decimal, 1), "-7.0");
+    assert_eq!(
+      encode(JsonValue::from("1234567890123456789.125"), &decimal, 2),
+      "1234567890123456789.13"
+    );
+
+    // Without a number of places they're written as they are
+    let cell = CellValue::encode(&JsonValue::from("1.005"), &decimal, &CellOptions::new());
+    assert_eq!(cell.unwrap().to_text(&CellOptions::new()), "1.005");
+  }
+
+  #[cfg(feature = "decimal")]
+  #[test]
+  fn decimal_exponents_round_exactly() {
+    use std::str::FromStr;
+    let value = Number::from_str("1.5e-7").unwrap();
+    assert_eq!(encode(JsonValue::Number(value), &number(), 7), "0.0000002");
+  }
+
+  #[cfg(not(feature = "decimal"))]
+  #[test]
+  fn exponents_round_as_floats() {
+    assert_eq!(CellValue::round_text("1.5e-7", 7), None);
+    assert_eq!(encode(JsonValue::from(1e-9), &number(), 2), "0.00");
+  }
+}
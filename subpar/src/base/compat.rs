@@ -0,0 +1,186 @@
+//! Structural comparison of row templates
+//!
+//! A sheet is laid out by one template and may be read or written by others. Comparing the columns
+//! of two templates tells whether data can safely flow between them in a given direction.
+
+use crate::local::*;
+
+use schemars::schema::{InstanceType, SchemaObject, SingleOrVec};
+
+/// How a single column differs from the base template to the other one
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColumnChange {
+  /// Only the base template has the column
+  Missing { required: bool },
+
+  /// Only the other template has the column. Defaulted columns can be filled in when reading.
+  Added { required: bool, has_default: bool },
+
+  /// The other template accepts everything the base does and more, such as integer to number
+  Widened {
+    from: Vec<InstanceType>,
+    to: Vec<InstanceType>,
+  },
+
+  /// The other template accepts only part of what the base does, such as number to integer
+  Narrowed {
+    from: Vec<InstanceType>,
+    to: Vec<InstanceType>,
+  },
+
+  /// Neither template accepts all of the other's values, such as boolean to integer
+  Incompatible {
+    from: Vec<InstanceType>,
+    to: Vec<InstanceType>,
+  },
+
+  /// The other template requires a column the base leaves optional
+  NowRequired,
+
+  /// The other template leaves optional a column the base requires
+  NoLongerRequired,
+}
+
+impl ColumnChange {
+  /// Whether the other template can read rows written by the base with this change
+  pub fn breaks_read(&self) -> bool {
+    match self {
+      ColumnChange::Added {
+        required,
+        has_default,
+      } => *required && !*has_default,
+      ColumnChange::Narrowed { .. }
+      | ColumnChange::Incompatible { .. }
+      | ColumnChange::NowRequired => true,
+      ColumnChange::Missing { .. }
+      | ColumnChange::Widened { .. }
+      | ColumnChange::NoLongerRequired => false,
+    }
+  }
+
+  /// Whether the other template can write rows into a sheet laid out by the base with this change
+  pub fn breaks_write(&self) -> bool {
+    match self {
+      ColumnChange::Missing { required } => *required,
+      ColumnChange::Added { .. }
+      | ColumnChange::Widened { .. }
+      | ColumnChange::Incompatible { .. }
+      | ColumnChange::NoLongerRequired => true,
+      ColumnChange::Narrowed { .. } | ColumnChange::NowRequired => false,
+    }
+  }
+
+  /// Compare the types two column schemas accept, ignoring null as that is up to required-ness
+  ///
+  /// Returns None if they accept the same values
+  pub fn compare_types(base: &SchemaObject, other: &SchemaObject) -> Option<ColumnChange> {
+    let from = instance_types(base);
+    let to = instance_types(other);
+
+    let widens = from.iter().all(|i_type| accepts(&to, i_type));
+    let narrows = to.iter().all(|i_type| accepts(&from, i_type));
+    match (widens, narrows) {
+      (true, true) => None,
+      (true, false) => Some(ColumnChange::Widened { from, to }),
+      (false, true) => Some(ColumnChange::Narrowed { from, to }),
+      (false, false) => Some(ColumnChange::Incompatible { from, to }),
+    }
+  }
+}
+
+/// The non-null types a column schema accepts. No type means anything goes.
+fn instance_types(schema: &SchemaObject) -> Vec<InstanceType> {
+  let mut types = match &schema.instance_type {
+    Some(SingleOrVec::Single(i_type)) => vec![**i_type],
+    Some(SingleOrVec::Vec(i_types)) => i_types.clone(),
+    None => vec![
+      InstanceType::Boolean,
+      InstanceType::Object,
+      InstanceType::Array,
+      InstanceType::Number,
+      InstanceType::String,
+    ],
+  };
+  types.retain(|i_type| *i_type != InstanceType::Null);
+  types.sort();
+  types.dedup();
+  types
+}
+
+/// Whether a column with the given types can hold a value of i_type without losing anything
+///
+/// Every cell in a sheet can be written as text, so strings accept any scalar
+fn accepts(types: &[InstanceType], i_type: &InstanceType) -> bool {
+  types.contains(i_type)
+    || match i_type {
+      InstanceType::Integer => {
+        types.contains(&InstanceType::Number) || types.contains(&InstanceType::String)
+      }
+      InstanceType::Number | InstanceType::Boolean => types.contains(&InstanceType::String),
+      _ => false,
+    }
+}
+
+/// A single column's change between two templates
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColumnDiff {
+  /// The dotted column name
+  pub name: String,
+  pub change: ColumnChange,
+}
+
+/// The structural differences between two row templates
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TemplateDiff {
+  /// The name of the template describing the sheet
+  pub base: String,
+
+  /// The name of the template being compared against it
+  pub other: String,
+
+  /// Every column that differs, sorted by name
+  pub changes: Vec<ColumnDiff>,
+}
+
+impl std::fmt::Display for TemplateDiff {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "'{}' -> '{}':", self.base, self.other)?;
+    for diff in &self.changes {
+      write!(f, "\n  {}: {:?}", diff.name, diff.change)?;
+    }
+    Ok(())
+  }
+}
+
+impl TemplateDiff {
+  /// The templates have identical columns, types and required-ness
+  pub fn is_identical(&self) -> bool {
+    self.changes.is_empty()
+  }
+
+  /// Changes that stop the other template from reading rows written with the base
+  pub fn read_problems(&self) -> Vec<&ColumnDiff> {
+    self
+      .changes
+      .iter()
+      .filter(|diff| diff.change.breaks_read())
+      .collect()
+  }
+
+  /// Changes that stop the other template from writing rows into a sheet laid out by the base
+  pub fn write_problems(&self) -> Vec<&ColumnDiff> {
+    self
+      .changes
+      .iter()
+      .filter(|diff| diff.change.breaks_write())
+      .collect()
+  }
+
+  pub fn can_read(&self) -> bool {
+    self.read_problems().is_empty()
+  }
+
+  pub fn can_write(&self) -> bool {
+    self.write_problems().is_empty()
+  }
+}
@@ -0,0 +1,568 @@
+//! Deserialize structs straight from a record's cells
+//!
+//! Going through a Row builds a JSON tree of the whole record, which serde_json then walks again.
+//! This deserializer walks the template's columns instead, converting each cell only when serde
+//! asks for its field. Nested structs follow the
+//! dotted column names. The first bad cell stops the record, rather than gathering every error
+//! like RowTemplate::to_row does.
+//!
+//! RawDeserializer does the same over a record's raw text, so plain text columns can be lent to
+//! &str and Cow<str> fields without copying them.
+
+use crate::local::*;
+
+use std::collections::HashMap;
+
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::forward_to_deserialize_any;
+use serde_json::Value as JsonValue;
+
+/// The columns of a template nested by their paths, in the order they are handed to serde
+#[derive(Clone, Debug, Default)]
+pub struct Layout {
+  entries: Vec<(String, Entry)>,
+}
+
+#[derive(Clone, Debug)]
+enum Entry {
+  /// A leaf column, by its dotted name
+  Column(String),
+  /// The columns of a nested struct
+  Group(Layout),
+}
+
+impl Layout {
+  /// Arrange the dotted column paths into a tree
+  pub fn new<'a, I>(paths: I) -> Layout
+  where
+    I: IntoIterator<Item = (&'a String, &'a [String])>,
+  {
+    let mut paths: Vec<_> = paths.into_iter().collect();
+    paths.sort();
+
+    let mut layout = Layout::default();
+    for (name, path) in paths {
+      layout.insert(name, path);
+    }
+    layout
+  }
+
+  fn insert(&mut self, name: &str, path: &[String]) {
+    let (first, rest) = match path.split_first() {
+      Some(split) => split,
+      None => return,
+    };
+
+    if rest.is_empty() {
+      self
+        .entries
+        .push((first.clone(), Entry::Column(name.to_string())));
+      return;
+    }
+
+    let position = self
+      .entries
+      .iter()
+      .position(|(key, entry)| key == first && matches!(entry, Entry::Group(_)));
+    let index = match position {
+      Some(index) => index,
+      None => {
+        self
+          .entries
+          .push((first.clone(), Entry::Group(Layout::default())));
+        self.entries.len() - 1
+      }
+    };
+    if let Entry::Group(group) = &mut self.entries[index].1 {
+      group.insert(name, rest);
+    }
+  }
+
+  /// Whether a field of this level is a column or a nested struct of columns
+  pub fn contains(&self, key: &str) -> bool {
+    self.entries.iter().any(|(name, _)| name == key)
+  }
+
+  /// Whether a nested struct has nothing to read, so an optional one becomes None
+  fn is_empty(&self, template: &RowTemplate, cells: &HashMap<String, Cell>) -> bool {
+    self.entries.iter().all(|(_, entry)| match entry {
+      Entry::Column(name) => {
+        cells.get(name).map(Cell::is_empty).unwrap_or(true) && !template.has_default(name)
+      }
+      Entry::Group(group) => group.is_empty(template, cells),
+    })
+  }
+}
+
+/// A serde Deserializer over one record's cells, converted using the template's columns
+pub struct RowDeserializer<'a> {
+  template: &'a RowTemplate,
+  cells: &'a HashMap<String, Cell>,
+  layout: &'a Layout,
+}
+
+impl<'a> RowDeserializer<'a> {
+  pub fn new(template: &'a RowTemplate, cells: &'a HashMap<String, Cell>) -> RowDeserializer<'a> {
+    RowDeserializer {
+      template,
+      cells,
+      layout: template.layout(),
+    }
+  }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for RowDeserializer<'a> {
+  type Error = SubparError;
+
+  fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+    visitor.visit_map(LayoutAccess {
+      template: self.template,
+      cells: self.cells,
+      entries: self.layout.entries.iter(),
+      pending: None,
+    })
+  }
+
+  fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+    match self.layout.is_empty(self.template, self.cells) {
+      true => visitor.visit_none(),
+      false => visitor.visit_some(self),
+    }
+  }
+
+  fn deserialize_newtype_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    visitor: V,
+  ) -> Result<V::Value> {
+    visitor.visit_newtype_struct(self)
+  }
+
+  forward_to_deserialize_any! {
+    bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+    bytes byte_buf unit unit_struct seq tuple
+    tuple_struct map struct enum identifier ignored_any
+  }
+}
+
+/// The value waiting for serde to ask for it, after its key was handed over
+enum Pending<'a> {
+  Value(JsonValue),
+  Group(&'a Layout),
+}
+
+struct LayoutAccess<'a> {
+  template: &'a RowTemplate,
+  cells: &'a HashMap<String, Cell>,
+  entries: std::slice::Iter<'a, (String, Entry)>,
+  pending: Option<Pending<'a>>,
+}
+
+impl<'de, 'a> MapAccess<'de> for LayoutAccess<'a> {
+  type Error = SubparError;
+
+  fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+    for (key, entry) in self.entries.by_ref() {
+      let pending = match entry {
+        // Missing optional cells are left out, the same as a row leaves them out
+        Entry::Column(name) => match self.template.cell_value(name, self.cells.get(name))? {
+          Some(value) => Pending::Value(value),
+          None => continue,
+        },
+        Entry::Group(group) => Pending::Group(group),
+      };
+      self.pending = Some(pending);
+      return seed.deserialize(key.as_str().into_deserializer()).map(Some);
+    }
+    Ok(None)
+  }
+
+  fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+    match self.pending.take() {
+      Some(Pending::Value(value)) => Ok(err_into!(seed.deserialize(value))?),
+      Some(Pending::Group(layout)) => seed.deserialize(RowDeserializer {
+        template: self.template,
+        cells: self.cells,
+        layout,
+      }),
+      None => Err(err!(Impossible, "Serde asked for a value before its key")),
+    }
+  }
+}
+
+/// The top level field names of a struct, in the order serde asks for them
+///
+/// A struct hands its field names over before reading anything, so this stops it right there.
+/// Maps and structs with flattened fields don't name their fields, and give None.
+pub fn field_names<'de, T: de::Deserialize<'de>>() -> Option<&'static [&'static str]> {
+  let mut names = None;
+  let _ = T::deserialize(FieldNames { names: &mut names });
+  names
+}
+
+/// Catches the field names of a struct, and fails every other request
+struct FieldNames<'a> {
+  names: &'a mut Option<&'static [&'static str]>,
+}
+
+impl<'de, 'a> de::Deserializer<'de> for FieldNames<'a> {
+  type Error = SubparError;
+
+  fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+    Err(err!(
+      Impossible,
+      "Only the field names of a struct are read"
+    ))
+  }
+
+  fn deserialize_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    fields: &'static [&'static str],
+    _visitor: V,
+  ) -> Result<V::Value> {
+    *self.names = Some(fields);
+    Err(err!(
+      Impossible,
+      "Only the field names of a struct are read"
+    ))
+  }
+
+  forward_to_deserialize_any! {
+    bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+    bytes byte_buf option unit unit_struct newtype_struct seq tuple
+    tuple_struct map enum identifier ignored_any
+  }
+}
+
+/// The raw text of one record, looked up by column name
+pub trait RawFields<'r>: Copy {
+  /// The text of the named column, or None if the record doesn't have one
+  fn text(&self, name: &str) -> Option<&'r str>;
+
+  /// Whether the text should be read as an empty cell
+  fn is_null(&self, name: &str, text: &str) -> bool;
+}
+
+/// A serde Deserializer over one record's raw text, borrowing plain text columns from it
+pub struct RawDeserializer<'r, F> {
+  template: &'r RowTemplate,
+  fields: F,
+  layout: &'r Layout,
+}
+
+impl<'r, F: RawFields<'r>> RawDeserializer<'r, F> {
+  pub fn new(template: &'r RowTemplate, fields: F) -> RawDeserializer<'r, F> {
+    RawDeserializer {
+      template,
+      fields,
+      layout: template.layout(),
+    }
+  }
+
+  /// Only read the columns in the layout, such as the ones a column map selected
+  pub fn with_layout(
+    template: &'r RowTemplate,
+    fields: F,
+    layout: &'r Layout,
+  ) -> RawDeserializer<'r, F> {
+    RawDeserializer {
+      template,
+      fields,
+      layout,
+    }
+  }
+
+  /// Whether a nested struct has nothing to read, so an optional one becomes None
+  fn is_empty(&self, layout: &Layout) -> bool {
+    layout.entries.iter().all(|(_, entry)| match entry {
+      Entry::Column(name) => {
+        let empty = match self.fields.text(name) {
+          Some(text) => self.fields.is_null(name, text),
+          None => true,
+        };
+        empty && !self.template.has_default(name)
+      }
+      Entry::Group(group) => self.is_empty(group),
+    })
+  }
+}
+
+impl<'de, 'r: 'de, F: RawFields<'r>> de::Deserializer<'de> for RawDeserializer<'r, F> {
+  type Error = SubparError;
+
+  fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+    visitor.visit_map(RawAccess {
+      template: self.template,
+      fields: self.fields,
+      entries: self.layout.entries.iter(),
+      pending: None,
+    })
+  }
+
+  fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+    match self.is_empty(self.layout) {
+      true => visitor.visit_none(),
+      false => visitor.visit_some(self),
+    }
+  }
+
+  fn deserialize_newtype_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    visitor: V,
+  ) -> Result<V::Value> {
+    visitor.visit_newtype_struct(self)
+  }
+
+  forward_to_deserialize_any! {
+    bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+    bytes byte_buf unit unit_struct seq tuple
+    tuple_struct map struct enum identifier ignored_any
+  }
+}
+
+/// Like Pending, but a filled in column is kept as text until serde says what it wants
+enum RawPending<'r> {
+  Text(&'r str, &'r str),
+  Value(JsonValue),
+  Group(&'r Layout),
+}
+
+struct RawAccess<'r, F> {
+  template: &'r RowTemplate,
+  fields: F,
+  entries: std::slice::Iter<'r, (String, Entry)>,
+  pending: Option<RawPending<'r>>,
+}
+
+impl<'de, 'r: 'de, F: RawFields<'r>> MapAccess<'de> for RawAccess<'r, F> {
+  type Error = SubparError;
+
+  fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+    for (key, entry) in self.entries.by_ref() {
+      let pending = match entry {
+        Entry::Column(name) => match self.fields.text(name) {
+          Some(text) if !self.fields.is_null(name, text) => RawPending::Text(name, text),
+          // Blank and missing columns get their defaults the same way cells do
+          text => {
+            let cell = text.map(|_| Cell::new(name.clone(), CellValue::Empty));
+            match self.template.cell_value(name, cell.as_ref())? {
+              Some(value) => RawPending::Value(value),
+              None => continue,
+            }
+          }
+        },
+        Entry::Group(group) => RawPending::Group(group),
+      };
+      self.pending = Some(pending);
+      return seed.deserialize(key.as_str().into_deserializer()).map(Some);
+    }
+    Ok(None)
+  }
+
+  fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+    match self.pending.take() {
+      Some(RawPending::Text(name, text)) => seed.deserialize(TextDeserializer {
+        template: self.template,
+        name,
+        text,
+      }),
+      Some(RawPending::Value(value)) => Ok(err_into!(seed.deserialize(value))?),
+      Some(RawPending::Group(layout)) => seed.deserialize(RawDeserializer {
+        template: self.template,
+        fields: self.fields,
+        layout,
+      }),
+      None => Err(err!(Impossible, "Serde asked for a value before its key")),
+    }
+  }
+}
+
+/// A single filled in column, lent out as is when it's plain text and converted otherwise
+struct TextDeserializer<'r> {
+  template: &'r RowTemplate,
+  name: &'r str,
+  text: &'r str,
+}
+
+impl<'r> TextDeserializer<'r> {
+  fn value(&self) -> Result<JsonValue> {
+    let cell = Cell::new(self.name.to_string(), CellValue::Raw(self.text.to_string()));
+    Ok(
+      self
+        .template
+        .cell_value(self.name, Some(&cell))?
+        .unwrap_or(JsonValue::Null),
+    )
+  }
+}
+
+impl<'de, 'r: 'de> de::Deserializer<'de> for TextDeserializer<'r> {
+  type Error = SubparError;
+
+  fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+    Ok(err_into!(de::Deserializer::deserialize_any(
+      self.value()?,
+      visitor
+    ))?)
+  }
+
+  fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+    match self.template.is_plain_text(self.name) {
+      true => visitor.visit_borrowed_str(self.text),
+      false => self.deserialize_any(visitor),
+    }
+  }
+
+  fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+    self.deserialize_str(visitor)
+  }
+
+  fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+    visitor.visit_some(self)
+  }
+
+  fn deserialize_newtype_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    visitor: V,
+  ) -> Result<V::Value> {
+    visitor.visit_newtype_struct(self)
+  }
+
+  fn deserialize_enum<V: Visitor<'de>>(
+    self,
+    name: &'static str,
+    variants: &'static [&'static str],
+    visitor: V,
+  ) -> Result<V::Value> {
+    Ok(err_into!(de::Deserializer::deserialize_enum(
+      self.value()?,
+      name,
+      variants,
+      visitor
+    ))?)
+  }
+
+  forward_to_deserialize_any! {
+    bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char
+    bytes byte_buf unit unit_struct seq tuple
+    tuple_struct map struct identifier ignored_any
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde::{Deserialize, Serialize};
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize, schemars::JsonSchema)]
+  struct Address {
+    city: String,
+    zip: Option<i64>,
+  }
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize, schemars::JsonSchema)]
+  struct Person {
+    name: String,
+    nickname: Option<String>,
+    home: Address,
+  }
+
+  #[derive(Debug, Deserialize)]
+  struct LentAddress<'r> {
+    city: &'r str,
+    zip: Option<i64>,
+  }
+
+  #[derive(Debug, Deserialize)]
+  struct Lent<'r> {
+    name: &'r str,
+    #[serde(borrow)]
+    nickname: Option<&'r str>,
+    #[serde(borrow)]
+    home: LentAddress<'r>,
+  }
+
+  /// A record's text by column name
+  #[derive(Clone, Copy)]
+  struct Text<'r>(&'r HashMap<String, String>);
+
+  impl<'r> RawFields<'r> for Text<'r> {
+    fn text(&self, name: &str) -> Option<&'r str> {
+      self.0.get(name).map(String::as_str)
+    }
+
+    fn is_null(&self, _name: &str, text: &str) -> bool {
+      text.is_empty()
+    }
+  }
+
+  fn template() -> RowTemplate {
+    schemars::schema_for!(Person).into()
+  }
+
+  fn ann() -> Person {
+    Person {
+      name: "Ann".to_string(),
+      nickname: None,
+      home: Address {
+        city: "Oslo".to_string(),
+        zip: Some(150),
+      },
+    }
+  }
+
+  #[test]
+  fn cells_make_nested_structs() {
+    let cells: HashMap<String, Cell> =
+      [("name", "Ann"), ("home.city", "Oslo"), ("home.zip", "0150")]
+        .iter()
+        .map(|(name, text)| {
+          let cell = Cell::new(name.to_string(), CellValue::Raw(text.to_string()));
+          (name.to_string(), cell)
+        })
+        .collect();
+
+    let person: Person = template().deserialize(&cells).unwrap();
+    assert_eq!(person, ann());
+  }
+
+  #[test]
+  fn rows_round_trip() {
+    let template = template();
+    let row = template.serialize(&ann()).unwrap();
+    assert_eq!(row.deserialize::<Person>().unwrap(), ann());
+
+    let mut named = ann();
+    named.nickname = Some("Annie".to_string());
+    named.home.zip = None;
+    let row = template.serialize(&named).unwrap();
+    assert_eq!(row.deserialize::<Person>().unwrap(), named);
+  }
+
+  #[test]
+  fn raw_text_is_lent_out() {
+    let template = template();
+    let text: HashMap<String, String> = [
+      ("name", "Ann"),
+      ("nickname", "Annie"),
+      ("home.city", "Oslo"),
+      ("home.zip", ""),
+    ]
+    .iter()
+    .map(|(name, text)| (name.to_string(), text.to_string()))
+    .collect();
+
+    let lent = Lent::deserialize(RawDeserializer::new(&template, Text(&text))).unwrap();
+    assert_eq!(lent.name, "Ann");
+    assert_eq!(lent.nickname, Some("Annie"));
+    assert_eq!(lent.home.city, "Oslo");
+    assert_eq!(lent.home.zip, None);
+
+    // Borrowed rather than copied
+    assert!(std::ptr::eq(lent.name, text["name"].as_str()));
+    assert!(std::ptr::eq(lent.home.city, text["home.city"].as_str()));
+  }
+}
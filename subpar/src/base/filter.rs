@@ -0,0 +1,128 @@
+//! Filter records on their raw text, before any of it is converted
+//!
+//! Converting the cells is most of the cost of reading a record. When only a few rows are wanted,
+//! a reader tests the text of each record first and throws away the misses without building
+//! anything from them.
+
+use crate::base::row::ColumnMap;
+use crate::local::*;
+
+use std::collections::HashSet;
+
+use regex::Regex;
+
+/// A test of one field's raw text
+#[derive(Clone, Debug)]
+pub enum Predicate {
+  /// The text is exactly this
+  Equals(String),
+  /// The text is any one of these
+  OneOf(HashSet<String>),
+  /// The text starts with this
+  Prefix(String),
+  /// The pattern is found somewhere in the text
+  Matches(Regex),
+}
+
+impl Predicate {
+  pub fn equals(text: &str) -> Predicate {
+    Predicate::Equals(text.to_string())
+  }
+
+  pub fn one_of(items: &[&str]) -> Predicate {
+    Predicate::OneOf(items.iter().map(|x| x.to_string()).collect())
+  }
+
+  pub fn prefix(text: &str) -> Predicate {
+    Predicate::Prefix(text.to_string())
+  }
+
+  /// Compile a regular expression. Anchor it with ^ and $ to match the whole field.
+  pub fn matches(pattern: &str) -> Result<Predicate> {
+    Ok(Predicate::Matches(err_into!(
+      Regex::new(pattern),
+      "Could not compile the filter pattern '{}'",
+      pattern
+    )?))
+  }
+
+  pub fn test(&self, text: &str) -> bool {
+    match self {
+      Predicate::Equals(value) => text == value,
+      Predicate::OneOf(values) => values.contains(text),
+      Predicate::Prefix(value) => text.starts_with(value.as_str()),
+      Predicate::Matches(pattern) => pattern.is_match(text),
+    }
+  }
+}
+
+/// Predicates on named columns. A record has to pass all of them to be read.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+  tests: Vec<(String, Predicate)>,
+}
+
+impl Filter {
+  pub fn new() -> Filter {
+    Filter::default()
+  }
+
+  /// Add a predicate on the column, for chaining
+  pub fn with(mut self, column: &str, predicate: Predicate) -> Filter {
+    self.add(column, predicate);
+    self
+  }
+
+  pub fn add(&mut self, column: &str, predicate: Predicate) {
+    self.tests.push((column.to_string(), predicate));
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.tests.is_empty()
+  }
+
+  /// Match the filter's columns to the fields of a file, so records can be tested by position
+  pub fn resolve(&self, map: &ColumnMap) -> Result<FieldFilter> {
+    let mut tests = Vec::with_capacity(self.tests.len());
+    for (column, predicate) in &self.tests {
+      match map.position(column) {
+        Some(position) => tests.push((position, predicate.clone())),
+        None => {
+          return Err(err!(
+            NotFound,
+            "Cannot filter on '{}', since the file has no column by that name",
+            column
+          ))
+        }
+      }
+    }
+    Ok(FieldFilter { tests })
+  }
+}
+
+/// A filter with its columns matched to the fields of one file
+#[derive(Clone, Debug, Default)]
+pub struct FieldFilter {
+  tests: Vec<(usize, Predicate)>,
+}
+
+impl FieldFilter {
+  /// Add the tests of another filter resolved against the same file
+  pub fn extend(&mut self, other: FieldFilter) {
+    self.tests.extend(other.tests);
+  }
+
+  /// Whether the record passes every test, reading fields by position. A short record fails.
+  pub fn test<'a, F>(&self, field: F) -> bool
+  where
+    F: Fn(usize) -> Option<&'a str>,
+  {
+    self
+      .tests
+      .iter()
+      .all(|(position, predicate)| match field(*position) {
+        Some(text) => predicate.test(text),
+        None => false,
+      })
+  }
+}
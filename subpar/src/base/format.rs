@@ -0,0 +1,192 @@
+//! Named string formats
+//!
+//! JSON Schema's "format" keyword names a kind of string, such as an email address or a date. The
+//! registry maps those names to the functions that check a value and, optionally, rewrite it into a
+//! canonical form. Formats the registry doesn't know are ignored, as the JSON Schema spec suggests.
+
+use crate::local::*;
+
+use std::collections::HashMap;
+
+/// Tests whether the text is a valid instance of the format
+pub type FormatCheck = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Rewrites valid text into the format's canonical form
+pub type FormatNormalizer = Arc<dyn Fn(&str) -> Result<String> + Send + Sync>;
+
+#[derive(Clone)]
+struct Format {
+  check: FormatCheck,
+  normalize: Option<FormatNormalizer>,
+}
+
+/// A lookup of format names to their validators and normalizers
+///
+/// The default registry includes email, uri, uuid, date, date-time and phone
+#[derive(Clone)]
+pub struct FormatRegistry {
+  formats: HashMap<String, Format>,
+}
+
+impl std::fmt::Debug for FormatRegistry {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let mut names: Vec<&String> = self.formats.keys().collect();
+    names.sort();
+    f.debug_struct("FormatRegistry")
+      .field("formats", &names)
+      .finish()
+  }
+}
+
+impl Default for FormatRegistry {
+  fn default() -> FormatRegistry {
+    let mut registry = FormatRegistry::empty();
+
+    registry.register("email", is_email);
+    registry.register("uri", |value| url::Url::parse(value).is_ok());
+    registry.register("uuid", |value| Uuid::parse_str(value).is_ok());
+    registry.register("date", |value| {
+      chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok()
+    });
+    registry.register("date-time", |value| {
+      chrono::DateTime::parse_from_rfc3339(value).is_ok()
+    });
+    registry.register("phone", is_phone);
+
+    // The built-ins are only registered above, so these can't fail
+    let _ = registry.register_normalizer("email", |value| {
+      // Only the domain is case insensitive
+      let value = value.trim();
+      let at = value.rfind('@').unwrap_or(0);
+      Ok(format!("{}{}", &value[..at], value[at..].to_lowercase()))
+    });
+    let _ = registry.register_normalizer("uri", |value| {
+      Ok(err_into!(url::Url::parse(value), "Invalid uri '{}'", value)?.to_string())
+    });
+    let _ = registry.register_normalizer("uuid", |value| {
+      Ok(
+        err_into!(Uuid::parse_str(value), "Invalid uuid '{}'", value)?
+          .to_hyphenated()
+          .to_string(),
+      )
+    });
+    let _ = registry.register_normalizer("date-time", |value| {
+      Ok(
+        err_into!(
+          chrono::DateTime::parse_from_rfc3339(value),
+          "Invalid date-time '{}'",
+          value
+        )?
+        .to_rfc3339(),
+      )
+    });
+    let _ = registry.register_normalizer("phone", |value| {
+      let digits: String = value.chars().filter(char::is_ascii_digit).collect();
+      match value.trim_start().starts_with('+') {
+        true => Ok(format!("+{}", digits)),
+        false => Ok(digits),
+      }
+    });
+
+    registry
+  }
+}
+
+impl FormatRegistry {
+  /// A registry with all the built-in formats
+  pub fn new() -> FormatRegistry {
+    Default::default()
+  }
+
+  /// A registry without any formats, for when the built-ins don't match the data
+  pub fn empty() -> FormatRegistry {
+    FormatRegistry {
+      formats: HashMap::new(),
+    }
+  }
+
+  /// Add or replace a format. Replacing a format also removes its normalizer.
+  pub fn register<F>(&mut self, name: &str, check: F)
+  where
+    F: Fn(&str) -> bool + Send + Sync + 'static,
+  {
+    self.formats.insert(
+      name.to_string(),
+      Format {
+        check: Arc::new(check),
+        normalize: None,
+      },
+    );
+  }
+
+  /// Set the function that rewrites a valid value of the format into its canonical form
+  pub fn register_normalizer<F>(&mut self, name: &str, normalize: F) -> Result<()>
+  where
+    F: Fn(&str) -> Result<String> + Send + Sync + 'static,
+  {
+    match self.formats.get_mut(name) {
+      Some(format) => {
+        format.normalize = Some(Arc::new(normalize));
+        Ok(())
+      }
+      None => Err(err!(
+        NotFound,
+        "Cannot add a normalizer for unknown format '{}'",
+        name
+      )),
+    }
+  }
+
+  pub fn contains(&self, name: &str) -> bool {
+    self.formats.contains_key(name)
+  }
+
+  /// Check the value against the named format, returning the normalized text if requested
+  ///
+  /// Unknown formats pass unchanged. Returns None when the value should be kept as is.
+  pub fn apply(&self, name: &str, value: &str, normalize: bool) -> Result<Option<String>> {
+    let format = match self.formats.get(name) {
+      Some(format) => format,
+      None => {
+        log::debug!("Skipping unknown format '{}' for value '{}'", name, value);
+        return Ok(None);
+      }
+    };
+
+    if !(format.check)(value) {
+      return Err(err!(InvalidFormat, "'{}' is not a valid {}", value, name));
+    }
+
+    match (&format.normalize, normalize) {
+      (Some(normalizer), true) => Ok(Some(normalizer(value)?)),
+      _ => Ok(None),
+    }
+  }
+}
+
+/// A loose address check: something before the @, and a dotted domain after it
+fn is_email(value: &str) -> bool {
+  let value = value.trim();
+  match value.rfind('@') {
+    Some(at) => {
+      let (local, domain) = (&value[..at], &value[at + 1..]);
+      !local.is_empty()
+        && !value.chars().any(char::is_whitespace)
+        && domain.contains('.')
+        && domain.split('.').all(|label| !label.is_empty())
+    }
+    None => false,
+  }
+}
+
+/// Digits with the usual punctuation, long enough to be a real number (E.164 allows up to 15)
+fn is_phone(value: &str) -> bool {
+  let value = value.trim();
+  let body = value.strip_prefix('+').unwrap_or(value);
+  let digits = body.chars().filter(char::is_ascii_digit).count();
+
+  body
+    .chars()
+    .all(|c| c.is_ascii_digit() || " -.()".contains(c))
+    && (7..=15).contains(&digits)
+}
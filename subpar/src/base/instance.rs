@@ -3,7 +3,6 @@
 //! Commonize the interface for manipulating an instance of a tabular.
 
 use crate::local::*;
-use anyhow::Result;
 
 /// Define the basic file access modes - read or write
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
@@ -45,7 +44,7 @@ impl Mode {
 ///
 /// This maps directly with Action messages.
 /// THINK: Does the builder pattern make sense for this trait?
-pub trait SubparWorkbook: std::fmt::Debug {
+pub trait SubparWorkbook: std::fmt::Debug + Send + Sync {
   /// Get an unique identifier based on the workbook
   fn get_id(&self) -> Result<Uuid>;
 
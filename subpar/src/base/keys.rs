@@ -0,0 +1,303 @@
+//! Sheet level constraints
+//!
+//! A key is a set of columns whose combined value must be unique across every row of a sheet. This
+//! can't be checked one row at a time, so readers keep a tracker of the keys they have already seen.
+
+use crate::local::*;
+
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value as JsonValue;
+
+/// A set of columns whose combined values must be unique within a sheet
+///
+/// Rows with an empty unique column are skipped, since there is nothing to compare. A primary key
+/// requires every column to have a value.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Key {
+  /// The dotted names of the columns making up the key, in order
+  columns: Vec<String>,
+
+  /// Whether this is the key identifying a row, such as for upserts and diffs
+  primary: bool,
+}
+
+impl std::fmt::Display for Key {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.primary {
+      true => write!(f, "primary key ({})", self.columns.join(", ")),
+      false => write!(f, "unique ({})", self.columns.join(", ")),
+    }
+  }
+}
+
+impl Key {
+  pub fn new(columns: Vec<String>, primary: bool) -> Key {
+    Key { columns, primary }
+  }
+
+  pub fn columns(&self) -> &[String] {
+    &self.columns
+  }
+
+  pub fn is_primary(&self) -> bool {
+    self.primary
+  }
+
+  /// Get the values of the key columns from a row
+  ///
+  /// Returns None when a unique key has an empty column, as it can't conflict with anything
+  pub fn extract(&self, row: &Row) -> Result<Option<Vec<JsonValue>>> {
+    let mut values = Vec::with_capacity(self.columns.len());
+    for column in &self.columns {
+      let value = row.get_cell(column).unwrap_or(JsonValue::Null);
+      if value.is_null() {
+        return match self.primary {
+          true => Err(err!(
+            NullValue,
+            "Column '{}' is part of the {} and cannot be empty",
+            column,
+            self
+          )),
+          false => Ok(None),
+        };
+      }
+      values.push(value);
+    }
+    Ok(Some(values))
+  }
+}
+
+/// Remembers each key value a reader has seen and the line it was first seen on
+#[derive(Debug, Default)]
+pub struct KeyTracker {
+  /// The serialized key values for each of the template's keys, in the same order
+  seen: Vec<HashMap<String, i64>>,
+}
+
+impl KeyTracker {
+  pub fn new() -> KeyTracker {
+    Default::default()
+  }
+
+  /// Record the row's keys, failing if any were already used by an earlier line
+  pub fn check(&mut self, keys: &[Key], row: &Row, line: i64) -> Result<()> {
+    let texts = self.test(keys, row, line)?;
+    self.record(texts, line);
+    Ok(())
+  }
+
+  /// The row's serialized keys, failing if any were already used by an earlier line
+  ///
+  /// Nothing is recorded, so the keys can be held back until the rest of the row is known to be
+  /// good, and then given to record.
+  pub fn test(&self, keys: &[Key], row: &Row, line: i64) -> Result<Vec<Option<String>>> {
+    let mut texts = Vec::with_capacity(keys.len());
+    for (i, key) in keys.iter().enumerate() {
+      let values = match key.extract(row)? {
+        Some(values) => values,
+        None => {
+          texts.push(None);
+          continue;
+        }
+      };
+
+      let text = err_into!(
+        serde_json::to_string(&values),
+        "Could not serialize the {} on line {}",
+        key,
+        line
+      )?;
+      if let Some(first) = self.seen.get(i).and_then(|seen| seen.get(&text)) {
+        return Err(err!(
+          DuplicateKey,
+          "Line {} has the same {} as line {}: {}",
+          line,
+          key,
+          first,
+          text
+        ));
+      }
+      texts.push(Some(text));
+    }
+    Ok(texts)
+  }
+
+  /// Remember the keys found by test as used by the line
+  pub fn record(&mut self, texts: Vec<Option<String>>, line: i64) {
+    if self.seen.len() < texts.len() {
+      self.seen.resize_with(texts.len(), HashMap::new);
+    }
+    for (text, seen) in texts.into_iter().zip(self.seen.iter_mut()) {
+      if let Some(text) = text {
+        seen.insert(text, line);
+      }
+    }
+  }
+}
+
+/// A link from columns in one sheet to a key in another, such as Orders.customer_id -> Customers.id
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForeignKey {
+  /// The sheet holding the references
+  pub sheet: String,
+
+  /// The referencing columns
+  pub columns: Vec<String>,
+
+  /// The sheet being referenced
+  pub ref_sheet: String,
+
+  /// The referenced columns, matched to the referencing columns by position
+  pub ref_columns: Vec<String>,
+}
+
+impl std::fmt::Display for ForeignKey {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}.{} -> {}.{}",
+      self.sheet,
+      self.columns.join(","),
+      self.ref_sheet,
+      self.ref_columns.join(",")
+    )
+  }
+}
+
+impl ForeignKey {
+  pub fn new(
+    sheet: &str,
+    columns: Vec<String>,
+    ref_sheet: &str,
+    ref_columns: Vec<String>,
+  ) -> Result<ForeignKey> {
+    if columns.is_empty() || columns.len() != ref_columns.len() {
+      return Err(err!(
+        BadValue,
+        "Foreign key from '{}' to '{}' needs the same number of columns on each side, got {:?} and {:?}",
+        sheet,
+        ref_sheet,
+        columns,
+        ref_columns
+      ));
+    }
+
+    Ok(ForeignKey {
+      sheet: sheet.to_string(),
+      columns,
+      ref_sheet: ref_sheet.to_string(),
+      ref_columns,
+    })
+  }
+
+  /// Parse a single column link written as "Sheet.column -> OtherSheet.column"
+  ///
+  /// The sheet name ends at the first dot, so nested columns such as Orders.address.zip work
+  pub fn parse(link: &str) -> Result<ForeignKey> {
+    let split = |side: &str| -> Result<(String, String)> {
+      let mut parts = side.trim().splitn(2, '.');
+      match (parts.next(), parts.next()) {
+        (Some(sheet), Some(column)) if !sheet.is_empty() && !column.is_empty() => {
+          Ok((sheet.to_string(), column.to_string()))
+        }
+        _ => Err(err!(
+          BadValue,
+          "Could not parse '{}' in foreign key '{}', expected 'Sheet.column'",
+          side,
+          link
+        )),
+      }
+    };
+
+    let mut sides = link.splitn(2, "->");
+    match (sides.next(), sides.next()) {
+      (Some(from), Some(to)) => {
+        let (sheet, column) = split(from)?;
+        let (ref_sheet, ref_column) = split(to)?;
+        ForeignKey::new(&sheet, vec![column], &ref_sheet, vec![ref_column])
+      }
+      _ => Err(err!(
+        BadValue,
+        "Could not parse foreign key '{}', expected 'Sheet.column -> OtherSheet.column'",
+        link
+      )),
+    }
+  }
+
+  /// The referencing values of a row, or None if any are empty since nulls reference nothing
+  pub fn extract(&self, row: &Row) -> Option<Vec<JsonValue>> {
+    Key::new(self.columns.clone(), false)
+      .extract(row)
+      .unwrap_or(None)
+  }
+
+  /// Serialize key values so they can be stored and compared as a single string
+  pub fn key_text(values: &[JsonValue]) -> Result<String> {
+    err_into!(serde_json::to_string(values))
+  }
+
+  /// The values a row can be referenced by
+  pub fn extract_referenced(&self, row: &Row) -> Option<Vec<JsonValue>> {
+    Key::new(self.ref_columns.clone(), false)
+      .extract(row)
+      .unwrap_or(None)
+  }
+}
+
+/// A row whose foreign key does not match any row in the referenced sheet
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DanglingReference {
+  /// The sheet containing the bad reference
+  pub sheet: String,
+
+  /// The record number within the sheet
+  pub line: i64,
+
+  /// The referencing column, comma separated for composite keys
+  pub column: String,
+
+  /// The value that could not be found. Composite keys are an array of the values
+  pub value: JsonValue,
+
+  /// The foreign key that was broken
+  pub foreign_key: ForeignKey,
+}
+
+impl std::fmt::Display for DanglingReference {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{} line {}: {} = {} has no match for {}",
+      self.sheet, self.line, self.column, self.value, self.foreign_key
+    )
+  }
+}
+
+impl DanglingReference {
+  /// Check the referencing values of each line against the values of the referenced sheet
+  ///
+  /// The known values are serialized with ForeignKey::key_text, so they compare the same way
+  pub fn find(
+    foreign_key: &ForeignKey,
+    references: Vec<(i64, Vec<JsonValue>)>,
+    known: &HashSet<String>,
+  ) -> Result<Vec<DanglingReference>> {
+    let mut dangling = Vec::new();
+    for (line, mut values) in references {
+      if !known.contains(&ForeignKey::key_text(&values)?) {
+        dangling.push(DanglingReference {
+          sheet: foreign_key.sheet.clone(),
+          line,
+          column: foreign_key.columns.join(", "),
+          value: match values.len() {
+            1 => values.remove(0),
+            _ => JsonValue::Array(values),
+          },
+          foreign_key: foreign_key.clone(),
+        });
+      }
+    }
+    Ok(dangling)
+  }
+}
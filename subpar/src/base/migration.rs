@@ -0,0 +1,287 @@
+//! Versioned templates
+//!
+//! A sheet's layout changes over time as columns are renamed or split. Each template has a version,
+//! and registered steps carry old rows forward one version at a time so archived files still load
+//! into today's structs. Steps work on the raw cells of a row, before any schema conversion, since
+//! the old layout has no schema of its own.
+
+use crate::local::*;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value as JsonValue;
+
+/// Moves a row's raw cells from one version to a later one
+///
+/// The value is a JSON object of header name to cell text, with empty cells as null
+pub type MigrationStep = Arc<dyn Fn(JsonValue) -> Result<JsonValue> + Send + Sync>;
+
+#[derive(Clone)]
+struct Migration {
+  to: u32,
+  step: MigrationStep,
+}
+
+/// The known layouts of a template's old versions and the steps between them
+#[derive(Clone, Default)]
+pub struct Migrations {
+  /// The headers each old version was written with, for detecting the version of a file
+  layouts: BTreeMap<u32, Vec<String>>,
+
+  /// The steps, by the version they start from
+  steps: BTreeMap<u32, Migration>,
+}
+
+impl std::fmt::Debug for Migrations {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let steps: Vec<(u32, u32)> = self
+      .steps
+      .iter()
+      .map(|(from, migration)| (*from, migration.to))
+      .collect();
+    f.debug_struct("Migrations")
+      .field("layouts", &self.layouts)
+      .field("steps", &steps)
+      .finish()
+  }
+}
+
+impl Migrations {
+  pub fn new() -> Migrations {
+    Default::default()
+  }
+
+  /// Record the headers an old version was written with
+  pub fn add_layout(&mut self, version: u32, headers: Vec<String>) {
+    self.layouts.insert(version, headers);
+  }
+
+  /// Add the step taking rows from one version to a later one
+  pub fn add_step<F>(&mut self, from: u32, to: u32, step: F) -> Result<()>
+  where
+    F: Fn(JsonValue) -> Result<JsonValue> + Send + Sync + 'static,
+  {
+    if to <= from {
+      return Err(err!(
+        BadValue,
+        "Migrations must move forward, but got version {} to {}",
+        from,
+        to
+      ));
+    }
+
+    if self.steps.contains_key(&from) {
+      return Err(err!(
+        DuplicateKey,
+        "There is already a migration starting at version {}",
+        from
+      ));
+    }
+
+    self.steps.insert(
+      from,
+      Migration {
+        to,
+        step: Arc::new(step),
+      },
+    );
+    Ok(())
+  }
+
+  /// Find the old version whose layout has exactly the given headers, ignoring order
+  pub fn detect(&self, headers: &[String]) -> Option<u32> {
+    let mut sorted = headers.to_vec();
+    sorted.sort();
+
+    self.layouts.iter().find_map(|(version, layout)| {
+      let mut layout = layout.clone();
+      layout.sort();
+      match layout == sorted {
+        true => Some(*version),
+        false => None,
+      }
+    })
+  }
+
+  /// Run each step from the starting version until reaching the target
+  pub fn migrate(&self, from: u32, to: u32, mut value: JsonValue) -> Result<JsonValue> {
+    let mut version = from;
+    while version < to {
+      let migration = self.steps.get(&version).ok_or_else(|| {
+        err!(
+          NotFound,
+          "No migration from version {} on the way from {} to {}",
+          version,
+          from,
+          to
+        )
+      })?;
+
+      value = (migration.step)(value).context(format!(
+        "Migrating from version {} to {}",
+        version, migration.to
+      ))?;
+      version = migration.to;
+    }
+
+    match version == to {
+      true => Ok(value),
+      false => Err(err!(
+        NotFound,
+        "The migrations from version {} skip over version {}",
+        from,
+        to
+      )),
+    }
+  }
+}
+
+/// The information kept beside a data file, such as the template version it was written with
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+  pub version: u32,
+}
+
+impl Manifest {
+  /// The manifest's location for a data file: the same path with ".manifest.json" appended
+  pub fn path_for(data: &Path) -> PathBuf {
+    let mut name = data.as_os_str().to_owned();
+    name.push(".manifest.json");
+    PathBuf::from(name)
+  }
+
+  /// Read the manifest for a data file, if one exists
+  pub fn read(data: &Path) -> Result<Option<Manifest>> {
+    let path = Manifest::path_for(data);
+    if !path.is_file() {
+      return Ok(None);
+    }
+
+    let text = err_into!(
+      std::fs::read_to_string(&path),
+      "Could not read manifest {:?}",
+      path
+    )?;
+    Ok(Some(err_into!(
+      serde_json::from_str(&text),
+      "Could not parse manifest {:?}",
+      path
+    )?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::csv::CsvReader;
+  use serde_json::json;
+
+  helpers::test_row!(Person {
+    first: String,
+    surname: String,
+  });
+
+  fn strings(items: &[&str]) -> Vec<String> {
+    items.iter().map(|x| x.to_string()).collect()
+  }
+
+  /// Version 1 had the whole name in one column, and version 2 called the surname "last"
+  fn person_v3() -> RowTemplate {
+    let mut template = Person::get_template();
+    template.set_version(3);
+    template.add_layout(1, &["name"]);
+    template.add_layout(2, &["first", "last"]);
+    template
+      .add_migration(1, 2, |row| {
+        let name = row["name"].as_str().unwrap_or_default().to_string();
+        let (first, last) = name.split_once(' ').unwrap_or((&name, ""));
+        Ok(json!({"first": first, "last": last}))
+      })
+      .unwrap();
+    template
+      .add_migration(2, 3, |row| {
+        Ok(json!({"first": row["first"], "surname": row["last"]}))
+      })
+      .unwrap();
+    template
+  }
+
+  fn ann_lee() -> Person {
+    Person {
+      first: "Ann".to_string(),
+      surname: "Lee".to_string(),
+    }
+  }
+
+  #[test]
+  fn versions_are_detected_from_headers() {
+    let template = person_v3();
+    assert_eq!(template.detect_version(&strings(&["name"])), 1);
+    assert_eq!(template.detect_version(&strings(&["last", "first"])), 2);
+    assert_eq!(template.detect_version(&strings(&["first", "surname"])), 3);
+    // Anything else is assumed to be current, and left for the header check to report
+    assert_eq!(template.detect_version(&strings(&["nickname"])), 3);
+
+    let mut migrations = Migrations::new();
+    migrations.add_layout(1, strings(&["a", "b"]));
+    assert_eq!(migrations.detect(&strings(&["b", "a"])), Some(1));
+    assert_eq!(migrations.detect(&strings(&["a"])), None);
+  }
+
+  #[test]
+  fn rows_migrate_one_step_at_a_time() {
+    let template = person_v3();
+    let row = template.migrate(1, json!({"name": "Ann Lee"})).unwrap();
+    assert_eq!(row, json!({"first": "Ann", "surname": "Lee"}));
+    let row = template.migrate(3, json!({"first": "Ann"})).unwrap();
+    assert_eq!(row, json!({"first": "Ann"}));
+  }
+
+  #[test]
+  fn missing_steps_are_errors() {
+    let mut migrations = Migrations::new();
+    migrations.add_step(1, 2, Ok).unwrap();
+    let err = migrations.migrate(1, 3, json!({})).unwrap_err();
+    assert!(matches!(err.kind(), Kind::NotFound));
+
+    // A step past the target can't stop at it
+    let mut migrations = Migrations::new();
+    migrations.add_step(1, 4, Ok).unwrap();
+    let err = migrations.migrate(1, 3, json!({})).unwrap_err();
+    assert!(matches!(err.kind(), Kind::NotFound));
+
+    assert!(migrations.add_step(1, 2, Ok).is_err());
+    assert!(migrations.add_step(3, 2, Ok).is_err());
+  }
+
+  #[test]
+  fn old_files_read_into_the_current_layout() {
+    let dir = helpers::test_dir();
+    let template = Arc::new(person_v3());
+    let read = |path: &Path| -> Result<Vec<Person>> {
+      CsvReader::new(
+        Accessor::Csv(path.to_path_buf()),
+        Some(template.clone()),
+        None,
+      )?
+      .map(|row| row.and_then(Person::try_from))
+      .collect()
+    };
+
+    // Detected from the headers
+    let v1 = dir.join("v1.csv");
+    std::fs::write(&v1, "name\nAnn Lee\n").unwrap();
+    assert_eq!(read(&v1).unwrap(), vec![ann_lee()]);
+
+    // An extra column hides the layout, so the manifest has to say which version it is
+    let v2 = dir.join("v2.csv");
+    std::fs::write(&v2, "first,last,note\nAnn,Lee,hi\n").unwrap();
+    assert!(read(&v2).is_err());
+    assert_eq!(Manifest::read(&v2).unwrap(), None);
+
+    std::fs::write(Manifest::path_for(&v2), r#"{"version": 2}"#).unwrap();
+    assert_eq!(Manifest::read(&v2).unwrap(), Some(Manifest { version: 2 }));
+    assert_eq!(read(&v2).unwrap(), vec![ann_lee()]);
+  }
+}
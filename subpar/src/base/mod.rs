@@ -1,25 +1,49 @@
 //! Generic items used regardless of tabular data
 
 // Metadata information for a workbook
-// pub mod state;
+pub mod state;
 
 // Data definitions of a generic workbook
-// pub mod workbook;
+pub mod workbook;
 
 // A sheet contained in a workbook
-// pub mod sheet;
+pub mod sheet;
 
 // An individually serialized item
 pub mod cell;
 
+// Accepted spellings and separators when converting cells
+pub mod options;
+
+// Validators for the schema's string formats
+pub mod format;
+
 // A row of a single sheet
 pub mod row;
 
+// Deserializing structs straight from a record's cells
+pub mod de;
+
+// Serializing structs into rows for the writers
+pub mod ser;
+
+// Skipping records by their raw text before converting them
+pub mod filter;
+
+// Uniqueness constraints across the rows of a sheet
+pub mod keys;
+
+// Checking whether two row templates can share a sheet
+pub mod compat;
+
+// Upgrading rows written with older versions of a template
+pub mod migration;
+
 // The info needed for an IO connection
 pub mod accessor;
 
 // The interface of a single workbook
-// pub mod instance;
+pub mod instance;
 
 // The communication api
 // pub mod messages;
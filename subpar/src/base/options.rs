@@ -0,0 +1,478 @@
+//! Settings for converting cell text
+//!
+//! Spreadsheet users write the same value many ways, such as "yes", "Y" or "x" for true, "N/A" for
+//! nothing, "$1,234.50" for a number, or "3/14/2021" for a date. These options tell the cell
+//! conversion which spellings to accept, and the encoder how to spell values going back out. A
+//! template has one set for all of its columns, and individual columns can replace it with their
+//! own.
+
+use crate::local::*;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+
+/// How the text of a cell is turned into a typed value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CellOptions {
+  /// Text accepted as true in boolean columns, compared case insensitively
+  pub truthy: Vec<String>,
+
+  /// Text accepted as false in boolean columns. Including "" makes a blank cell false.
+  pub falsy: Vec<String>,
+
+  /// The separator between the items of an array kept in a single cell
+  pub delimiter: String,
+
+  /// Removes leading and trailing whitespace from each array item
+  pub trim_items: bool,
+
+  /// Text that means the cell has no value, such as "N/A" or "NULL", compared case insensitively
+  pub null_values: Vec<String>,
+
+  /// What writers put in the cell for a missing value
+  pub null_output: String,
+
+  /// The character between the whole and fractional parts of a number
+  pub decimal_separator: char,
+
+  /// The character grouping the digits of large numbers, which is removed before parsing
+  pub group_separator: Option<char>,
+
+  /// Symbols allowed before or after a number, such as "$" or "EUR"
+  pub currency: Vec<String>,
+
+  /// Read a number wrapped in parentheses, such as "(45)", as negative
+  pub accounting_negatives: bool,
+
+  /// Read "12%" as 0.12. If false, a percent sign makes the cell an invalid number.
+  pub scale_percent: bool,
+
+  /// strftime patterns tried in order for columns with the "date" format
+  pub date_formats: Vec<String>,
+
+  /// strftime patterns tried in order for "date-time" columns, after RFC 3339. Date patterns are
+  /// tried last, giving midnight.
+  pub datetime_formats: Vec<String>,
+
+  /// strftime patterns tried in order for "time" columns
+  pub time_formats: Vec<String>,
+
+  /// The offset given to date times written without one. Defaults to UTC.
+  pub timezone: FixedOffset,
+
+  /// What writers put in a boolean cell for true
+  pub true_output: String,
+
+  /// What writers put in a boolean cell for false
+  pub false_output: String,
+
+  /// Round written numbers to this many decimal places. None writes them as they are.
+  pub decimal_places: Option<usize>,
+
+  /// The strftime pattern writers use for dates
+  pub date_output: String,
+
+  /// The strftime pattern writers use for date times, in the default timezone
+  pub datetime_output: String,
+}
+
+impl Default for CellOptions {
+  fn default() -> CellOptions {
+    let to_vec = |items: &[&str]| items.iter().map(|x| x.to_string()).collect();
+    CellOptions {
+      truthy: to_vec(&["true", "yes", "y", "1", "x"]),
+      falsy: to_vec(&["false", "no", "n", "0", ""]),
+      delimiter: ",".to_string(),
+      trim_items: true,
+      null_values: to_vec(&[""]),
+      null_output: String::new(),
+      decimal_separator: '.',
+      group_separator: Some(','),
+      currency: to_vec(&["$", "€", "£", "¥"]),
+      accounting_negatives: true,
+      scale_percent: true,
+      date_formats: to_vec(&["%Y-%m-%d", "%m/%d/%Y", "%Y/%m/%d", "%d %b %Y", "%b %d, %Y"]),
+      datetime_formats: to_vec(&[
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%d %H:%M",
+        "%m/%d/%Y %H:%M:%S",
+        "%m/%d/%Y %H:%M",
+        "%m/%d/%Y %I:%M %p",
+      ]),
+      time_formats: to_vec(&["%H:%M:%S%.f", "%H:%M", "%I:%M:%S %p", "%I:%M %p"]),
+      timezone: FixedOffset::east_opt(0).unwrap(),
+      true_output: "true".to_string(),
+      false_output: "false".to_string(),
+      decimal_places: None,
+      date_output: "%Y-%m-%d".to_string(),
+      datetime_output: "%Y-%m-%d %H:%M:%S%.f".to_string(),
+    }
+  }
+}
+
+impl CellOptions {
+  pub fn new() -> CellOptions {
+    Default::default()
+  }
+
+  /// Numbers written as "1.234,50", with array items separated by semicolons instead
+  pub fn decimal_comma() -> CellOptions {
+    CellOptions {
+      decimal_separator: ',',
+      group_separator: Some('.'),
+      delimiter: ";".to_string(),
+      ..Default::default()
+    }
+  }
+
+  /// Match the text against the true and false spellings
+  pub fn parse_bool(&self, text: &str) -> Option<bool> {
+    if matches_any(&self.truthy, text) {
+      Some(true)
+    } else if matches_any(&self.falsy, text) {
+      Some(false)
+    } else {
+      None
+    }
+  }
+
+  /// Whether the text is one of the null sentinels
+  pub fn is_null(&self, text: &str) -> bool {
+    matches_any(&self.null_values, text)
+  }
+
+  /// Split a cell into its array items. A blank cell has no items.
+  pub fn split_items<'a>(&self, text: &'a str) -> Vec<&'a str> {
+    if text.trim().is_empty() {
+      return Vec::new();
+    }
+
+    text
+      .split(self.delimiter.as_str())
+      .map(|item| match self.trim_items {
+        true => item.trim(),
+        false => item,
+      })
+      .collect()
+  }
+
+  /// Rewrite a number as typed by a person into plain JSON number text
+  ///
+  /// This removes digit grouping, currency symbols and whitespace, turns accounting parentheses
+  /// into a minus sign and scales percentages, so "($1,234.50)" becomes "-1234.50".
+  pub fn normalize_number(&self, text: &str) -> Result<String> {
+    let mut rest = text.trim();
+    let mut negative = false;
+
+    if self.accounting_negatives && rest.starts_with('(') && rest.ends_with(')') {
+      negative = true;
+      rest = rest[1..rest.len() - 1].trim();
+    }
+
+    let percent = rest.ends_with('%');
+    if percent {
+      if !self.scale_percent {
+        return Err(err!(
+          ConversionError,
+          "'{}' is a percentage, which is not allowed in this column",
+          text
+        ));
+      }
+      rest = rest[..rest.len() - 1].trim();
+    }
+
+    // Signs and currency symbols can come in either order, like "-$12" or "$-12"
+    loop {
+      let before = rest;
+      if let Some(stripped) = rest.strip_prefix('-') {
+        negative = !negative;
+        rest = stripped;
+      } else if let Some(stripped) = rest.strip_prefix('+') {
+        rest = stripped;
+      }
+      for symbol in self.currency.iter().filter(|x| !x.is_empty()) {
+        rest = rest.strip_prefix(symbol.as_str()).unwrap_or(rest);
+        rest = rest.strip_suffix(symbol.as_str()).unwrap_or(rest);
+      }
+      rest = rest.trim();
+      if rest == before {
+        break;
+      }
+    }
+
+    self.check_grouping(text, rest)?;
+
+    let mut number = String::with_capacity(rest.len() + 1);
+    for c in rest.chars() {
+      match c {
+        c if Some(c) == self.group_separator => (),
+        c if c == self.decimal_separator => number.push('.'),
+        c if c.is_whitespace() => (),
+        '0'..='9' | 'e' | 'E' | '+' | '-' => number.push(c),
+        _ => {
+          return Err(err!(
+            ConversionError,
+            "'{}' is not a number: unexpected character '{}'",
+            text,
+            c
+          ))
+        }
+      }
+    }
+
+    if number.is_empty() || number.starts_with(|c: char| !c.is_ascii_digit() && c != '.') {
+      return Err(err!(ConversionError, "'{}' is not a number", text));
+    }
+    if number.starts_with('.') {
+      number.insert(0, '0');
+    }
+    if percent {
+      number = shift_decimal(&number, 2);
+    }
+    if negative {
+      number.insert(0, '-');
+    }
+    Ok(number)
+  }
+
+  /// Make sure digit groups come in threes before the decimal separator, like "1,234,567"
+  ///
+  /// A misplaced separator is more likely a typo or the wrong locale than a grouping, so "1,5" or
+  /// "1.234.5" is an error instead of being read as 15 or 12345.
+  fn check_grouping(&self, text: &str, number: &str) -> Result<()> {
+    let group = match self.group_separator {
+      Some(group) if number.contains(group) => group,
+      _ => return Ok(()),
+    };
+    let (whole, fraction) = match number.find(self.decimal_separator) {
+      Some(i) => number.split_at(i),
+      None => (number, ""),
+    };
+
+    let digits = |piece: &str| piece.chars().take_while(char::is_ascii_digit).count();
+    let pieces: Vec<&str> = whole.split(group).collect();
+    let last = pieces.len() - 1;
+    let grouped = pieces.iter().enumerate().all(|(i, piece)| match i {
+      0 => (1..=3).contains(&piece.len()) && digits(piece) == piece.len(),
+      // The last group can run into an exponent
+      i if i == last => digits(piece) == 3,
+      _ => piece.len() == 3 && digits(piece) == 3,
+    });
+
+    match grouped && !fraction.contains(group) {
+      true => Ok(()),
+      false => Err(err!(
+        ConversionError,
+        "'{}' is not a number: its digits aren't grouped by '{}' in threes",
+        text,
+        group
+      )),
+    }
+  }
+
+  /// Rewrite a date or time into ISO 8601 if the schema format asks for one
+  ///
+  /// "date" gives "2021-03-14", "time" gives "13:05:00" and "date-time" gives RFC 3339 with an
+  /// offset. chrono's naive types use "partial-date-time", which gives a date time without an
+  /// offset or just a time, depending on what the text holds. Returns None for other formats.
+  pub fn normalize_date(&self, format: &str, text: &str) -> Result<Option<String>> {
+    let text = text.trim();
+    let normalized = match format {
+      "date" => self
+        .parse_date(text)
+        .map(|x| x.format("%Y-%m-%d").to_string()),
+      "time" => self
+        .parse_time(text)
+        .map(|x| x.format("%H:%M:%S%.f").to_string()),
+      "date-time" => self.parse_datetime(text).map(|x| x.to_rfc3339()),
+      "partial-date-time" => match self.parse_naive_datetime(text) {
+        Some(x) => Some(x.format("%Y-%m-%dT%H:%M:%S%.f").to_string()),
+        None => self
+          .parse_time(text)
+          .map(|x| x.format("%H:%M:%S%.f").to_string()),
+      },
+      _ => return Ok(None),
+    };
+
+    match normalized {
+      Some(value) => Ok(Some(value)),
+      None => Err(err!(
+        ConversionError,
+        "'{}' does not match any of the accepted {} patterns",
+        text,
+        format
+      )),
+    }
+  }
+
+  /// Write a date time from a typed sheet as ISO 8601 text for the schema format
+  ///
+  /// "date-time" gets the default timezone's offset, while the others drop the parts they don't
+  /// hold.
+  pub fn write_datetime(&self, format: Option<&str>, value: &NaiveDateTime) -> String {
+    match format {
+      Some("date") => value.format("%Y-%m-%d").to_string(),
+      Some("time") => value.format("%H:%M:%S%.f").to_string(),
+      Some("date-time") => match self.timezone.from_local_datetime(value).single() {
+        Some(value) => value.to_rfc3339(),
+        None => value.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+      },
+      _ => value.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+    }
+  }
+
+  fn parse_date(&self, text: &str) -> Option<NaiveDate> {
+    self
+      .date_formats
+      .iter()
+      .find_map(|fmt| NaiveDate::parse_from_str(text, fmt).ok())
+  }
+
+  fn parse_time(&self, text: &str) -> Option<NaiveTime> {
+    self
+      .time_formats
+      .iter()
+      .find_map(|fmt| NaiveTime::parse_from_str(text, fmt).ok())
+  }
+
+  fn parse_naive_datetime(&self, text: &str) -> Option<NaiveDateTime> {
+    self
+      .datetime_formats
+      .iter()
+      .find_map(|fmt| NaiveDateTime::parse_from_str(text, fmt).ok())
+      .or_else(|| self.parse_date(text).and_then(|x| x.and_hms_opt(0, 0, 0)))
+  }
+
+  fn parse_datetime(&self, text: &str) -> Option<DateTime<FixedOffset>> {
+    // Patterns with an offset (%z) keep it, and the rest are placed in the default timezone
+    DateTime::parse_from_rfc3339(text)
+      .ok()
+      .or_else(|| {
+        self
+          .datetime_formats
+          .iter()
+          .find_map(|fmt| DateTime::parse_from_str(text, fmt).ok())
+      })
+      .or_else(|| {
+        self
+          .parse_naive_datetime(text)
+          .and_then(|x| self.timezone.from_local_datetime(&x).single())
+      })
+  }
+}
+
+/// Compare text to a list of spellings, ignoring case and surrounding whitespace
+pub fn matches_any(spellings: &[String], text: &str) -> bool {
+  let text = text.trim();
+  spellings
+    .iter()
+    .any(|x| x.trim().eq_ignore_ascii_case(text))
+}
+
+/// Divide plain number text by a power of ten without going through floating point
+fn shift_decimal(number: &str, places: usize) -> String {
+  let (mantissa, exponent) = match number.find(['e', 'E']) {
+    Some(i) => number.split_at(i),
+    None => (number, ""),
+  };
+  let (whole, fraction) = match mantissa.find('.') {
+    Some(i) => (&mantissa[..i], &mantissa[i + 1..]),
+    None => (mantissa, ""),
+  };
+
+  let whole = format!("{:0>width$}", whole, width = places + 1);
+  let (whole, moved) = whole.split_at(whole.len() - places);
+  let fraction = format!("{}{}", moved, fraction);
+  let fraction = fraction.trim_end_matches('0');
+
+  match fraction {
+    "" => format!("{}{}", whole, exponent),
+    _ => format!("{}.{}{}", whole, fraction, exponent),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn french() -> CellOptions {
+    CellOptions {
+      group_separator: Some('\u{202f}'),
+      ..CellOptions::decimal_comma()
+    }
+  }
+
+  #[test]
+  fn numbers_follow_the_locale() {
+    let english = CellOptions::new();
+    assert_eq!(english.normalize_number("$1,234.50").unwrap(), "1234.50");
+    assert_eq!(english.normalize_number("1,234,567").unwrap(), "1234567");
+    assert_eq!(english.normalize_number(".5").unwrap(), "0.5");
+
+    let german = CellOptions::decimal_comma();
+    assert_eq!(german.normalize_number("1.234,50 €").unwrap(), "1234.50");
+    assert_eq!(german.normalize_number("-0,5").unwrap(), "-0.5");
+
+    let french = french();
+    assert_eq!(french.normalize_number("1\u{202f}234,5").unwrap(), "1234.5");
+    assert_eq!(french.normalize_number("1 234,5").unwrap(), "1234.5");
+  }
+
+  #[test]
+  fn accounting_negatives() {
+    let options = CellOptions::new();
+    assert_eq!(options.normalize_number("(1,234.50)").unwrap(), "-1234.50");
+    assert_eq!(options.normalize_number("($45)").unwrap(), "-45");
+    assert_eq!(options.normalize_number("(-45)").unwrap(), "45");
+
+    let plain = CellOptions {
+      accounting_negatives: false,
+      ..Default::default()
+    };
+    assert!(plain.normalize_number("(45)").is_err());
+  }
+
+  #[test]
+  fn percentages_are_scaled() {
+    let options = CellOptions::new();
+    assert_eq!(options.normalize_number("12.5%").unwrap(), "0.125");
+    assert_eq!(options.normalize_number("1e3%").unwrap(), "0.01e3");
+    assert_eq!(options.normalize_number("(5%)").unwrap(), "-0.05");
+
+    let plain = CellOptions {
+      scale_percent: false,
+      ..Default::default()
+    };
+    assert!(plain.normalize_number("12%").is_err());
+  }
+
+  #[test]
+  fn decimals_shift_without_rounding() {
+    assert_eq!(shift_decimal("12.5", 2), "0.125");
+    assert_eq!(shift_decimal("1250", 2), "12.5");
+    assert_eq!(shift_decimal("100", 2), "1");
+    assert_eq!(shift_decimal("0.1", 3), "0.0001");
+    assert_eq!(shift_decimal("2E-4", 1), "0.2E-4");
+  }
+
+  #[test]
+  fn malformed_grouping_is_rejected() {
+    let english = CellOptions::new();
+    for text in [
+      "1,5",
+      "12,34",
+      "1,2345",
+      "1234,567",
+      ",123",
+      "1.234,5",
+      "1,234.567,8",
+    ] {
+      assert!(english.normalize_number(text).is_err(), "{}", text);
+    }
+    assert_eq!(english.normalize_number("1,234e3").unwrap(), "1234e3");
+
+    let german = CellOptions::decimal_comma();
+    assert!(german.normalize_number("1.5").is_err());
+    assert!(german.normalize_number("1.234.5").is_err());
+  }
+}
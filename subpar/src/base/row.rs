@@ -3,11 +3,20 @@
 //! An individual row on a sheet. This is composed of cells encoded into a serde_json::Value. From
 //! this value, we can convert any subset into a specific type or even send it across the wire.
 
+use crate::base::{
+    compat::{ColumnChange, ColumnDiff, TemplateDiff},
+    de::{Layout, RowDeserializer},
+    format::FormatRegistry,
+    keys::Key,
+    migration::Migrations,
+    options::CellOptions,
+    ser::RowSerializer,
+};
 use crate::local::*;
 
 use std::{
     any::{Any, TypeId},
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
     hash::{Hash, Hasher},
 };
@@ -21,6 +30,9 @@ use schemars::schema::{
 /// Some traits to attach to for a schemars RootSchema
 // This is hacky, but just need to get it working as validation is not yet stored
 trait SchemaTools {
+    /// Follow references and single member wrappers until reaching a concrete schema
+    fn resolve<'a>(&'a self, schema: &'a Schema) -> Option<&'a SchemaObject>;
+
     /// Retrieve the schema at the location of the dot separated path. Empty returns the root
     fn get_subschema(&self, path: &str) -> Result<&SchemaObject>;
 
@@ -41,31 +53,81 @@ trait SchemaTools {
 }
 
 impl SchemaTools for RootSchema {
+    fn resolve<'a>(&'a self, schema: &'a Schema) -> Option<&'a SchemaObject> {
+        let obj = match schema {
+            Schema::Object(obj) => obj,
+            Schema::Bool(_) => return None,
+        };
+
+        // Schemars puts named structs in the definitions as "#/definitions/{name}"
+        if let Some(reference) = &obj.reference {
+            let name = reference.rsplit('/').next()?;
+            return self.resolve(self.definitions.get(name)?);
+        }
+
+        // Option<T> and annotated fields get wrapped in an anyOf/allOf with a single real member
+        if let Some(subschemas) = &obj.subschemas {
+            let members = subschemas.all_of.as_ref().or(subschemas.any_of.as_ref());
+            if let Some(members) = members {
+                let mut concrete = members.iter().filter(|member| !is_null_schema(member));
+                if let (Some(only), None) = (concrete.next(), concrete.next()) {
+                    return self.resolve(only);
+                }
+            }
+        }
+
+        Some(obj)
+    }
+
     fn get_subschema(&self, path: &str) -> Result<&SchemaObject> {
-        let tokens = path.split('.');
-        let res = tokens.fold(Ok(&self.schema), |acc, token| {
-            if let Ok(current) = acc {
-                let schema = current.object.as_ref().unwrap();
-                match schema.properties.get(path) {
-                    Some(Schema::Object(next)) => Ok(next),
-                    Some(_) => Err(err!(
-                        NotFound,
-                        "Could not find object schema for token {} in path {}",
-                        token,
-                        path
-                    )),
-                    None => Err(err!(
+        if path.is_empty() {
+            return Ok(&self.schema);
+        }
+
+        path.split('.').try_fold(&self.schema, |current, token| {
+            let next = current
+                .object
+                .as_ref()
+                .and_then(|validation| validation.properties.get(token))
+                .ok_or_else(|| {
+                    err!(
                         NotFound,
                         "Could not find schema for token {} in path {}",
                         token,
                         path
-                    )),
-                }
-            } else {
-                acc
-            }
-        })?;
-        Ok(res)
+                    )
+                })?;
+
+            self.resolve(next).ok_or_else(|| {
+                err!(
+                    NotFound,
+                    "Could not find object schema for token {} in path {}",
+                    token,
+                    path
+                )
+            })
+        })
+    }
+}
+
+/// Whether the schema only allows null, such as the second half of an Option<T>
+fn is_null_schema(schema: &Schema) -> bool {
+    match schema {
+        Schema::Object(SchemaObject {
+            instance_type: Some(SingleOrVec::Single(i_type)),
+            ..
+        }) => **i_type == InstanceType::Null,
+        _ => false,
+    }
+}
+
+/// The properties of an object schema that should be split into dotted columns
+///
+/// Objects without properties (maps, raw json) are kept as a single column
+fn nested_properties(schema: &SchemaObject) -> Option<&ObjectValidation> {
+    match &schema.object {
+        Some(validation) if !validation.properties.is_empty() => Some(validation),
+        _ => None,
     }
 }
 
@@ -129,8 +191,223 @@ pub trait SubparRow: TryFrom<Row> + TryInto<Row> + Any + std::fmt::Debug + Sized
 #[derive(Clone, Debug)]
 struct Column {
     _name: String,
-    _validation: Box<SchemaObject>,
+    /// The keys leading to the cell in the row. Nested columns use the dotted header name.
+    path: Vec<String>,
+    validation: Box<SchemaObject>,
     required: bool,
+    /// The schema's "default" keyword, used when the cell is missing or empty
+    default: Option<JsonValue>,
+}
+
+/// A template's columns matched to the fields of one file
+///
+/// This is built once when the file is opened, so each record can be converted by position
+/// without looking columns up by name or copying the header names into every cell.
+#[derive(Clone, Debug)]
+pub struct ColumnMap {
+    /// The file's fields, in the order they appear in a record
+    fields: Vec<MappedField>,
+    /// The position of each header, for finding a field by name
+    positions: HashMap<Arc<str>, usize>,
+    /// The template's columns to convert, sorted by name
+    columns: Vec<MappedColumn>,
+    /// The columns to convert nested by their paths, for deserializing
+    layout: Layout,
+    /// Whether a selected column reads the field at each position
+    used: Vec<bool>,
+}
+
+#[derive(Clone, Debug)]
+struct MappedField {
+    /// The header, shared with the template column it fills
+    header: Arc<str>,
+    /// The options for reading the field's text
+    options: CellOptions,
+}
+
+#[derive(Clone, Debug)]
+struct MappedColumn {
+    name: Arc<str>,
+    /// The field holding the column's value, if the file has it
+    position: Option<usize>,
+    column: Column,
+}
+
+impl MappedColumn {
+    /// Whether the column is the named one, or nested somewhere beneath it
+    fn is_under(&self, name: &str) -> bool {
+        match self.name.strip_prefix(name) {
+            Some(rest) => rest.is_empty() || rest.starts_with('.'),
+            None => false,
+        }
+    }
+}
+
+impl ColumnMap {
+    /// The file's headers, in the order of a record's fields
+    pub fn headers(&self) -> impl Iterator<Item = &Arc<str>> {
+        self.fields.iter().map(|field| &field.header)
+    }
+
+    /// The position of the named field in a record
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.positions.get(name).copied()
+    }
+
+    /// Keep only the named columns, so the rest of each record is never converted
+    ///
+    /// A name can also be the start of a dotted path, so "home" keeps every column of home.
+    pub fn select<S: AsRef<str>>(&mut self, names: &[S]) -> Result<()> {
+        let unknown: Vec<&str> = names
+            .iter()
+            .map(|name| name.as_ref())
+            .filter(|name| !self.columns.iter().any(|mapped| mapped.is_under(name)))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(err!(
+                UnknownColumn,
+                "Cannot select {:?}, since the template has no columns by those names",
+                unknown
+            ));
+        }
+
+        self.project(names);
+        Ok(())
+    }
+
+    /// Keep only the named columns, like select, ignoring names the template doesn't have
+    pub fn project<S: AsRef<str>>(&mut self, names: &[S]) {
+        self.columns
+            .retain(|mapped| names.iter().any(|name| mapped.is_under(name.as_ref())));
+        self.layout = ColumnMap::layout_of(&self.columns);
+        self.used = ColumnMap::used_of(&self.columns, self.fields.len());
+    }
+
+    /// Whether a selected column reads the field at the position
+    pub fn is_used(&self, position: usize) -> bool {
+        self.used.get(position).copied().unwrap_or(false)
+    }
+
+    fn used_of(columns: &[MappedColumn], len: usize) -> Vec<bool> {
+        let mut used = vec![false; len];
+        for position in columns.iter().filter_map(|mapped| mapped.position) {
+            used[position] = true;
+        }
+        used
+    }
+
+    /// Whether the named template column is converted
+    pub fn is_selected(&self, name: &str) -> bool {
+        self.columns
+            .binary_search_by(|mapped| (*mapped.name).cmp(name))
+            .is_ok()
+    }
+
+    /// The selected columns nested by their paths
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn layout_of(columns: &[MappedColumn]) -> Layout {
+        Layout::new(
+            columns
+                .iter()
+                .map(|mapped| (&mapped.column._name, &mapped.column.path[..])),
+        )
+    }
+
+    /// Whether the text of the field at the position reads as an empty cell
+    pub fn is_null(&self, position: usize, text: &str) -> bool {
+        match self.fields.get(position) {
+            Some(field) => field.options.is_null(text),
+            None => true,
+        }
+    }
+}
+
+/// A function that creates a value for a column the sheet did not fill in
+///
+/// This is for values that can't be written in a schema, such as a new Uuid or today's date
+#[derive(Clone)]
+pub struct ComputedDefault(Arc<dyn Fn() -> JsonValue + Send + Sync>);
+
+impl std::fmt::Debug for ComputedDefault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ComputedDefault")
+    }
+}
+
+/// A required header that was not found in the sheet
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MissingHeader {
+    /// The column name the template expected
+    pub name: String,
+
+    /// The unexpected header in the sheet closest to the name, if any is close enough to be a typo
+    pub suggestion: Option<String>,
+}
+
+/// The differences between a sheet's header row and a row template
+///
+/// This is kept as data rather than an error message so a sheet editor can show it to the user
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderReport {
+    /// Required columns with no matching header
+    pub missing: Vec<MissingHeader>,
+
+    /// Headers in the sheet that the template does not know about
+    pub unexpected: Vec<String>,
+
+    /// Headers that appear more than once in the sheet, where the last one is read
+    pub duplicates: Vec<String>,
+}
+
+impl std::fmt::Display for HeaderReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let missing: Vec<String> = self
+            .missing
+            .iter()
+            .map(|header| match &header.suggestion {
+                Some(suggestion) => format!("'{}' (did you mean '{}'?)", header.name, suggestion),
+                None => format!("'{}'", header.name),
+            })
+            .collect();
+
+        write!(
+            f,
+            "missing: [{}], unexpected: {:?}, duplicates: {:?}",
+            missing.join(", "),
+            self.unexpected,
+            self.duplicates
+        )
+    }
+}
+
+impl HeaderReport {
+    /// The sheet can be read if nothing required is missing
+    ///
+    /// Duplicates are only reported, as the later column wins when a record is read
+    pub fn is_valid(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Find the candidate with the smallest edit distance, ignoring case
+    ///
+    /// Anything further than a third of the name's length away is not considered a typo
+    fn closest(name: &str, candidates: &[String]) -> Option<String> {
+        let lower = name.to_lowercase();
+        let limit = std::cmp::max(1, lower.chars().count() / 3);
+
+        candidates
+            .iter()
+            .map(|candidate| {
+                let distance = helpers::edit_distance(&lower, &candidate.to_lowercase());
+                (distance, candidate)
+            })
+            .filter(|(distance, _)| *distance <= limit)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate.clone())
+    }
 }
 
 /// Describe how a row is expected to look
@@ -144,7 +421,25 @@ pub struct RowTemplate {
     /// A lookup for each column's validation
     columns: HashMap<String, Column>,
     /// the full schema of the row
-    schema: Rc<RootSchema>,
+    schema: Arc<RootSchema>,
+    /// Generated values for columns that are missing or empty, checked before the schema default
+    defaults: HashMap<String, ComputedDefault>,
+    /// Column sets that must be unique across the sheet. The primary key, if any, is always first.
+    keys: Vec<Key>,
+    /// Validators for columns whose schema declares a string format
+    formats: FormatRegistry,
+    /// Whether valid formatted strings are rewritten into their canonical form
+    normalize_formats: bool,
+    /// The current version of the layout, which rows from older files are migrated up to
+    version: u32,
+    /// The older layouts and the steps to bring their rows up to date
+    migrations: Migrations,
+    /// How cell text is converted for all columns
+    options: CellOptions,
+    /// Columns that convert their cells differently from the rest of the template
+    column_options: HashMap<String, CellOptions>,
+    /// The columns nested by path, for deserializing straight from cells
+    layout: Layout,
 }
 
 impl std::fmt::Display for RowTemplate {
@@ -158,26 +453,7 @@ impl RowTemplate {
         let (columns, schema) = match schema {
             Some(schema) => {
                 let mut headers = HashMap::new();
-                if let Some(validation) = &schema.schema.object {
-                    for (key, value) in validation.properties.iter() {
-                        if let Schema::Object(obj) = value {
-                            headers.insert(
-                                key.clone(),
-                                Column {
-                                    _name: key.clone(),
-                                    _validation: Box::new(obj.clone()),
-                                    required: false,
-                                },
-                            );
-                        };
-                    }
-                    // And tag the required columns
-                    for name in &validation.required {
-                        if let Some(col) = headers.get_mut(name) {
-                            (*col).required = true
-                        }
-                    }
-                };
+                RowTemplate::collect_columns(&schema, &schema.schema, &[], true, &mut headers);
                 (headers, schema)
             }
             None => (HashMap::new(), RowTemplate::blank_schema(name.clone())),
@@ -185,8 +461,74 @@ impl RowTemplate {
 
         RowTemplate {
             name,
+            layout: RowTemplate::layout_of(&columns),
             columns,
-            schema: Rc::new(schema),
+            schema: Arc::new(schema),
+            defaults: HashMap::new(),
+            keys: Vec::new(),
+            formats: FormatRegistry::new(),
+            normalize_formats: false,
+            options: CellOptions::default(),
+            column_options: HashMap::new(),
+            version: 1,
+            migrations: Migrations::new(),
+        }
+    }
+
+    /// Walk the properties of an object, making a column out of each leaf
+    ///
+    /// Nested objects are named by their dotted path (eg. address.street), and are only required if
+    /// every object above them is also required.
+    fn collect_columns(
+        root: &RootSchema,
+        object: &SchemaObject,
+        prefix: &[String],
+        required: bool,
+        columns: &mut HashMap<String, Column>,
+    ) {
+        let validation = match &object.object {
+            Some(validation) => validation,
+            None => return,
+        };
+
+        for (key, value) in validation.properties.iter() {
+            let schema = match root.resolve(value) {
+                Some(schema) => schema,
+                None => continue,
+            };
+
+            let mut path = prefix.to_vec();
+            path.push(key.clone());
+            let required = required && validation.required.contains(key);
+
+            // Annotated fields keep their default on the wrapper rather than the resolved schema
+            let default = match value {
+                Schema::Object(SchemaObject {
+                    metadata: Some(meta),
+                    ..
+                }) if meta.default.is_some() => meta.default.clone(),
+                _ => schema
+                    .metadata
+                    .as_ref()
+                    .and_then(|meta| meta.default.clone()),
+            };
+
+            match nested_properties(schema) {
+                Some(_) => RowTemplate::collect_columns(root, schema, &path, required, columns),
+                None => {
+                    let name = path.join(".");
+                    columns.insert(
+                        name.clone(),
+                        Column {
+                            _name: name,
+                            path,
+                            validation: Box::new(schema.clone()),
+                            required,
+                            default,
+                        },
+                    );
+                }
+            }
         }
     }
 
@@ -210,38 +552,34 @@ impl RowTemplate {
         self.name.clone()
     }
 
+    /// Get the validation for a column, using the dotted name for nested columns
     pub fn get_cell_schema(&self, name: &str) -> Result<&SchemaObject> {
-        let root = match &self.schema.schema.object {
-            Some(validation) => validation,
-            None => {
-                return Err(err!(
-                    NotFound,
-                    "Row Template {} does not have a useable schema",
-                    self.name
-                ))
-            }
-        };
-
-        let schema = (*root).properties.get(name).ok_or_else(|| {
-            err!(
+        match self.columns.get(name) {
+            Some(column) => Ok(&column.validation),
+            None => Err(err!(
                 NotFound,
                 "No column named '{}' in row template '{}'",
                 name,
                 self.name
-            )
-        })?;
-
-        match schema {
-            Schema::Object(value) => Ok(value),
-            _ => Err(err!(
-        NotFound,
-        "The column named '{}' in row template '{}' did not have a proper schema configured",
-        name,
-        self.name
-      )),
+            )),
         }
     }
 
+    /// Turn a row's value back into a cell for the named column, for writing
+    ///
+    /// Columns the template doesn't know are encoded without a schema
+    pub fn encode(&self, name: &str, value: &JsonValue) -> Result<CellValue> {
+        let default = SchemaObject::default();
+        let schema = match self.columns.get(name) {
+            Some(column) => &column.validation,
+            None => &default,
+        };
+        CellValue::encode(value, schema, self.get_options(name)).context(format!(
+            "Could not encode column '{}' of '{}'",
+            name, self.name
+        ))
+    }
+
     pub fn get_validation(&self) -> Result<&ObjectValidation> {
         match &self.schema.schema.object {
             Some(validation) => Ok(validation),
@@ -260,55 +598,262 @@ impl RowTemplate {
     /// This both converts and runs any validation listed in the schema, accumulating any validation
     /// errors found
     pub fn to_row(&self, cells: HashMap<String, Cell>) -> Result<Row> {
-        let root = self.get_validation()?;
+        self.to_row_where(cells, |_| true)
+    }
+
+    /// Convert the cells of the columns a map selected, along with any the keep function asks for
+    pub fn to_row_selected<F>(
+        &self,
+        cells: HashMap<String, Cell>,
+        map: &ColumnMap,
+        keep: F,
+    ) -> Result<Row>
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.to_row_where(cells, |name| map.is_selected(name) || keep(name))
+    }
 
+    fn to_row_where<F: Fn(&str) -> bool>(
+        &self,
+        cells: HashMap<String, Cell>,
+        keep: F,
+    ) -> Result<Row> {
         // THINK: Is this best moved to schemars as a generic?
-        let row = BatchResult::fold(
+        let mut row = BatchResult::fold(
             Row::new(Some(self)),
-            root.properties.iter(),
-            |row: &mut Row, (name, obj)| {
-                let cell = match cells.get(name) {
-                    Some(val) => val,
-                    None => {
-                        if root.required.contains(name) {
-                            return Err(err!(
-                NotFound,
-                "Row Template '{}' requires a column named '{}' but did not receive one",
-                self.name,
-                name
-              ));
-                        } else {
-                            return Ok(());
-                        }
-                    }
-                };
+            self.columns.iter().filter(|(name, _)| keep(name)),
+            |row: &mut Row, (name, column)| match self.column_value(
+                name,
+                column,
+                self.get_options(name),
+                cells.get(name).map(Cell::value),
+            )? {
+                Some(value) => row.add_cell_at(&column.path, value),
+                None => Ok(()),
+            },
+        )
+        .context("Unable to convert cells to a row".to_string())
+        .as_result::<SubparError>()?;
 
-                let schema = match &obj {
-                    Schema::Object(val) => val,
-                    _ => {
-                        return Err(err!(
-                            NotFound,
-                            "Cell {} Row Template {} does not have a useable schema",
-                            name,
-                            self.name
-                        ))
-                    }
-                };
+        row.prune_empty();
+        Ok(row)
+    }
 
-                match cell.to_value(schema) {
-                    Ok(value) => row.add_cell(name, value),
-                    Err(err) => Err(err).context(format!(
-                        "Could not convert cell '{}' to a value for row '{}'",
-                        name, self.name
-                    )),
+    /// Convert a record read by position, using a column map built from the file's headers
+    pub fn to_row_at(&self, map: &ColumnMap, values: &[CellValue]) -> Result<Row> {
+        let mut row = BatchResult::fold(
+            Row::new(Some(self)),
+            map.columns.iter(),
+            |row: &mut Row, mapped: &MappedColumn| {
+                let value = mapped.position.and_then(|i| values.get(i));
+                let opts = match mapped.position {
+                    Some(i) => &map.fields[i].options,
+                    None => self.get_options(&mapped.name),
+                };
+                match self.column_value(&mapped.name, &mapped.column, opts, value)? {
+                    Some(value) => row.add_cell_at(&mapped.column.path, value),
+                    None => Ok(()),
                 }
             },
         )
         .context("Unable to convert cells to a row".to_string())
         .as_result::<SubparError>()?;
+
+        row.prune_empty();
         Ok(row)
     }
 
+    /// Match the template's columns to a file's headers, so its records can be read by position
+    pub fn map_columns(&self, headers: &[String]) -> ColumnMap {
+        let fields: Vec<MappedField> = headers
+            .iter()
+            .map(|header| MappedField {
+                header: Arc::from(header.as_str()),
+                options: self.get_options(header).clone(),
+            })
+            .collect();
+
+        // Later duplicates win, the same as they do when a record is gathered by name
+        let positions: HashMap<Arc<str>, usize> = fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| (field.header.clone(), i))
+            .collect();
+
+        let mut columns: Vec<MappedColumn> = self
+            .columns
+            .iter()
+            .map(|(name, column)| {
+                let position = positions.get(name.as_str()).copied();
+                MappedColumn {
+                    name: match position {
+                        Some(i) => fields[i].header.clone(),
+                        None => Arc::from(name.as_str()),
+                    },
+                    position,
+                    column: column.clone(),
+                }
+            })
+            .collect();
+        columns.sort_by(|a, b| a.name.cmp(&b.name));
+
+        ColumnMap {
+            layout: ColumnMap::layout_of(&columns),
+            used: ColumnMap::used_of(&columns, fields.len()),
+            fields,
+            positions,
+            columns,
+        }
+    }
+
+    /// Deserialize a record's cells directly into T, without building a row
+    ///
+    /// This converts the same way as to_row, but stops at the first bad cell and skips the
+    /// template's keys, which need a whole row to check.
+    pub fn deserialize<T: serde::de::DeserializeOwned>(
+        &self,
+        cells: &HashMap<String, Cell>,
+    ) -> Result<T> {
+        T::deserialize(RowDeserializer::new(self, cells))
+            .with_context(|| format!("Could not deserialize a record of '{}'", self.name))
+    }
+
+    /// Serialize a struct or map into a row of this template, ready for a writer
+    pub fn serialize<T: serde::Serialize>(&self, item: &T) -> Result<Row> {
+        item.serialize(RowSerializer::new(self))
+            .with_context(|| format!("Could not serialize a row of '{}'", self.name))
+    }
+
+    /// The columns nested by their paths
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn layout_of(columns: &HashMap<String, Column>) -> Layout {
+        Layout::new(
+            columns
+                .iter()
+                .map(|(name, column)| (name, &column.path[..])),
+        )
+    }
+
+    /// Whether a column keeps its text as is, so a reader can lend it out instead of converting it
+    pub fn is_plain_text(&self, name: &str) -> bool {
+        let column = match self.columns.get(name) {
+            Some(column) => column,
+            None => return false,
+        };
+        let is_text = match &column.validation.instance_type {
+            Some(SingleOrVec::Single(i_type)) => **i_type == InstanceType::String,
+            // Optional strings are tried as text first, so a filled in cell is still the same text
+            Some(SingleOrVec::Vec(i_types)) => {
+                i_types.first() == Some(&InstanceType::String)
+                    && i_types[1..].iter().all(|x| *x == InstanceType::Null)
+            }
+            None => false,
+        };
+        is_text && column.validation.format.is_none() && !CellValue::is_decimal(&column.validation)
+    }
+
+    /// Convert the cell for the named column, giving None if it's missing but not required
+    pub fn cell_value(&self, name: &str, cell: Option<&Cell>) -> Result<Option<JsonValue>> {
+        match self.columns.get(name) {
+            Some(column) => {
+                self.column_value(name, column, self.get_options(name), cell.map(Cell::value))
+            }
+            None => Err(err!(
+                NotFound,
+                "No column named '{}' in row template '{}'",
+                name,
+                self.name
+            )),
+        }
+    }
+
+    fn column_value(
+        &self,
+        name: &str,
+        column: &Column,
+        opts: &CellOptions,
+        cell: Option<&CellValue>,
+    ) -> Result<Option<JsonValue>> {
+        // Defaults only fill in the blanks, they never replace a value from the sheet
+        let default = match cell {
+            Some(cell) if !cell.is_empty() => None,
+            _ => self.get_default(name),
+        };
+
+        match (default, cell) {
+            (Some(value), _) => Ok(Some(value)),
+            (None, Some(cell)) => cell
+                .to_value(&column.validation, opts)
+                .and_then(|value| self.check_format(column, value))
+                .map(Some)
+                .with_context(|| {
+                    format!(
+                        "Could not convert cell '{}' to a value for row '{}'",
+                        name, self.name
+                    )
+                }),
+            (None, None) if column.required => Err(err!(
+                NotFound,
+                "Row Template '{}' requires a column named '{}' but did not receive one",
+                self.name,
+                name
+            )),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Validate a string against the column's format, normalizing it if the template asks to
+    fn check_format(&self, column: &Column, value: JsonValue) -> Result<JsonValue> {
+        match (&column.validation.format, &value) {
+            (Some(format), JsonValue::String(text)) => {
+                match self.formats.apply(format, text, self.normalize_formats)? {
+                    Some(normalized) => Ok(JsonValue::String(normalized)),
+                    None => Ok(value),
+                }
+            }
+            _ => Ok(value),
+        }
+    }
+
+    /// The format validators used by the template, for registering custom formats
+    pub fn formats_mut(&mut self) -> &mut FormatRegistry {
+        &mut self.formats
+    }
+
+    /// Rewrite formatted strings into their canonical form while converting, such as lower casing
+    /// the domain of an email address
+    pub fn set_normalize_formats(&mut self, normalize: bool) {
+        self.normalize_formats = normalize;
+    }
+
+    /// Set how cell text is converted for every column without options of its own
+    pub fn set_options(&mut self, options: CellOptions) {
+        self.options = options;
+    }
+
+    /// Give a single column its own conversion options, such as a different array delimiter
+    pub fn set_column_options(&mut self, name: &str, options: CellOptions) -> Result<()> {
+        if !self.columns.contains_key(name) {
+            return Err(err!(
+                NotFound,
+                "Cannot set options for column '{}' because template '{}' does not have it",
+                name,
+                self.name
+            ));
+        }
+        self.column_options.insert(name.to_string(), options);
+        Ok(())
+    }
+
+    /// The options used to convert the named column's cells
+    pub fn get_options(&self, name: &str) -> &CellOptions {
+        self.column_options.get(name).unwrap_or(&self.options)
+    }
+
     pub fn get_headers(&self) -> Result<Vec<String>> {
         match self.columns.len() {
             0 => Err(err!(
@@ -319,26 +864,251 @@ impl RowTemplate {
         }
     }
 
+    /// Compare a sheet's header row against the template
+    ///
+    /// This never fails because of a mismatch, it only describes it. Use validate_headers to turn
+    /// the problems into an error.
+    pub fn check_headers(&self, headers: &[String]) -> HeaderReport {
+        let mut report = HeaderReport::default();
+
+        let mut seen = HashSet::new();
+        for header in headers {
+            if !seen.insert(header) {
+                if !report.duplicates.contains(header) {
+                    report.duplicates.push(header.clone());
+                }
+            } else if !self.columns.contains_key(header) {
+                report.unexpected.push(header.clone());
+            }
+        }
+
+        let mut required: Vec<&String> = self
+            .columns
+            .iter()
+            .filter(|(name, column)| column.required && !self.has_default(name))
+            .map(|(name, _)| name)
+            .collect();
+        required.sort();
+
+        for name in required {
+            if !seen.contains(name) {
+                report.missing.push(MissingHeader {
+                    name: name.clone(),
+                    suggestion: HeaderReport::closest(name, &report.unexpected),
+                });
+            }
+        }
+
+        report
+    }
+
     /// Check that all the headers are found in the schema
     pub fn validate_headers(&self, headers: &Vec<String>) -> Result<()> {
-        let root = self.get_validation()?;
-        let mut remaining = root.required.clone();
+        let report = self.check_headers(headers);
+        if !report.is_valid() {
+            return Err(err!(
+                BadValue,
+                "The headers do not match row template '{}': {}",
+                self.name,
+                report
+            ));
+        }
+        Ok(())
+    }
 
-        for header in headers {
-            if root.properties.contains_key(header) {
-                remaining.remove(header);
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Set the current version of the layout. Templates start at version 1.
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    /// Record the headers an older version of the template was written with
+    ///
+    /// These are used to detect the version of a file without a manifest
+    pub fn add_layout(&mut self, version: u32, headers: &[&str]) {
+        self.migrations
+            .add_layout(version, headers.iter().map(|x| x.to_string()).collect());
+    }
+
+    /// Add a step that moves a row's raw cells from one version to a later one
+    ///
+    /// The step receives a JSON object of header to cell text, with empty cells as null
+    pub fn add_migration<F>(&mut self, from: u32, to: u32, step: F) -> Result<()>
+    where
+        F: Fn(JsonValue) -> Result<JsonValue> + Send + Sync + 'static,
+    {
+        self.migrations.add_step(from, to, step)
+    }
+
+    /// Figure out which version of the template a file's headers were written with
+    ///
+    /// Headers that fit the current template are the current version, even if they happen to match
+    /// an old layout too.
+    pub fn detect_version(&self, headers: &[String]) -> u32 {
+        match self.check_headers(headers).is_valid() {
+            true => self.version,
+            false => self.migrations.detect(headers).unwrap_or(self.version),
+        }
+    }
+
+    /// Bring a row's raw cells from an older version up to the current one
+    pub fn migrate(&self, from: u32, raw: JsonValue) -> Result<JsonValue> {
+        self.migrations
+            .migrate(from, self.version, raw)
+            .context(format!("Could not migrate row template '{}'", self.name))
+    }
+
+    /// Compare the columns of another template against this one
+    ///
+    /// This template is treated as the base, describing the sheet as it is
+    pub fn compare(&self, other: &RowTemplate) -> TemplateDiff {
+        let mut names: Vec<&String> = self
+            .columns
+            .keys()
+            .chain(
+                other
+                    .columns
+                    .keys()
+                    .filter(|name| !self.columns.contains_key(*name)),
+            )
+            .collect();
+        names.sort();
+
+        let mut changes = Vec::new();
+        for name in names {
+            let mut push = |change| {
+                changes.push(ColumnDiff {
+                    name: name.clone(),
+                    change,
+                })
             };
+
+            match (self.columns.get(name), other.columns.get(name)) {
+                (Some(base), None) => push(ColumnChange::Missing {
+                    required: base.required,
+                }),
+                (None, Some(col)) => push(ColumnChange::Added {
+                    required: col.required,
+                    has_default: other.has_default(name),
+                }),
+                (Some(base), Some(col)) => {
+                    if let Some(change) =
+                        ColumnChange::compare_types(&base.validation, &col.validation)
+                    {
+                        push(change);
+                    }
+                    match (base.required, col.required) {
+                        (false, true) => push(ColumnChange::NowRequired),
+                        (true, false) => push(ColumnChange::NoLongerRequired),
+                        _ => (),
+                    }
+                }
+                (None, None) => (),
+            }
+        }
+
+        TemplateDiff {
+            base: self.name.clone(),
+            other: other.name.clone(),
+            changes,
         }
-        if !remaining.is_empty() {
+    }
+
+    /// Register a function to fill in a column when the sheet leaves it missing or empty
+    ///
+    /// This takes priority over the default keyword in the column's schema
+    pub fn add_default<F>(&mut self, name: &str, default: F) -> Result<()>
+    where
+        F: Fn() -> JsonValue + Send + Sync + 'static,
+    {
+        if !self.columns.contains_key(name) {
             return Err(err!(
-                BadValue,
-                "The following required headers were not found: {:?}",
-                remaining
+                NotFound,
+                "Cannot add a default for column '{}' because row template '{}' does not have it",
+                name,
+                self.name
             ));
         }
+
+        self.defaults
+            .insert(name.to_string(), ComputedDefault(Arc::new(default)));
+        Ok(())
+    }
+
+    /// Whether a missing column can be filled in with a computed or schema default
+    pub fn has_default(&self, name: &str) -> bool {
+        self.defaults.contains_key(name)
+            || self
+                .columns
+                .get(name)
+                .map(|column| column.default.is_some())
+                .unwrap_or(false)
+    }
+
+    /// Get the value to use for a missing or empty column, if it has one
+    pub fn get_default(&self, name: &str) -> Option<JsonValue> {
+        match self.defaults.get(name) {
+            Some(ComputedDefault(default)) => Some(default()),
+            None => self
+                .columns
+                .get(name)
+                .and_then(|column| column.default.clone()),
+        }
+    }
+
+    /// Require the combined values of the columns to be unique across the sheet
+    pub fn add_unique(&mut self, columns: &[&str]) -> Result<()> {
+        let key = self.build_key(columns, false)?;
+        self.keys.push(key);
+        Ok(())
+    }
+
+    /// Set the columns that identify a row, replacing any existing primary key
+    pub fn set_primary_key(&mut self, columns: &[&str]) -> Result<()> {
+        let key = self.build_key(columns, true)?;
+        self.keys.retain(|key| !key.is_primary());
+        self.keys.insert(0, key);
         Ok(())
     }
 
+    fn build_key(&self, columns: &[&str], primary: bool) -> Result<Key> {
+        if columns.is_empty() {
+            return Err(err!(
+                BadValue,
+                "A key for row template '{}' needs at least one column",
+                self.name
+            ));
+        }
+
+        for name in columns {
+            if !self.columns.contains_key(*name) {
+                return Err(err!(
+                    NotFound,
+                    "Cannot use column '{}' in a key because row template '{}' does not have it",
+                    name,
+                    self.name
+                ));
+            }
+        }
+
+        Ok(Key::new(
+            columns.iter().map(|name| name.to_string()).collect(),
+            primary,
+        ))
+    }
+
+    /// All the uniqueness constraints on the sheet, starting with the primary key
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    pub fn primary_key(&self) -> Option<&Key> {
+        self.keys.iter().find(|key| key.is_primary())
+    }
+
     /// Add a new column definition. This should build a proper SchemaObject".to_string()
     ///
     /// This uses try_mut to attempt to clean up after any failed
@@ -359,11 +1129,16 @@ impl RowTemplate {
 
         let column = Column {
             _name: name.to_string(),
-            _validation: Box::new(schema.clone()),
+            path: vec![name.to_string()],
+            validation: Box::new(schema.clone()),
             required,
+            default: schema
+                .metadata
+                .as_ref()
+                .and_then(|meta| meta.default.clone()),
         };
 
-        if self.columns.insert(name.to_string(), column).is_some() {
+        if self.columns.contains_key(name) {
             return Err(err!(
                 DuplicateKey,
                 "Duplicate headers named '{}' in table '{}'",
@@ -371,31 +1146,23 @@ impl RowTemplate {
                 self.name
             ));
         };
+        self.columns.insert(name.to_string(), column);
+        self.layout = RowTemplate::layout_of(&self.columns);
 
-        // We expect the root to be an object with the column headers as keys
-        let mut validation: Box<ObjectValidation> =
-            self.schema.schema.object.clone().unwrap_or_default();
+        // We expect the root to be an object with the column headers as keys. Rows already made
+        // keep the schema they were built with.
+        let root = Arc::make_mut(&mut self.schema);
+        let validation = root.schema.object.get_or_insert_with(Default::default);
 
         validation
             .properties
-            .insert(name.to_string(), Schema::Object(schema.clone()));
+            .insert(name.to_string(), Schema::Object(schema));
 
         if required {
             validation.required.insert(name.to_string());
         }
-        /*
-        Ok(RowTemplate {
-          schema: RootSchema {
-            schema: SchemaObject {
-              object: Some(validation),
-              ..self.schema.schema
-            },
-            ..self.schema
-          },
-          ..self
-        })
-        */
-        unimplemented!("'Add Column' still needs to be implemented")
+
+        Ok(())
     }
 
     /// Let serde deceide what it should be, if there is no hint given
@@ -427,7 +1194,7 @@ impl From<RootSchema> for RowTemplate {
 #[derive(Debug)]
 pub struct Row {
     /// Pointer to the row's template
-    schema: Rc<RootSchema>,
+    schema: Arc<RootSchema>,
 
     /// The contents of the parsed structure
     cells: JsonValue,
@@ -445,46 +1212,153 @@ impl Row {
         Row {
             schema: match template {
                 Some(tmp) => tmp.schema.clone(),
-                None => Rc::new(RowTemplate::blank_schema("No Name".to_string())),
+                None => Arc::new(RowTemplate::blank_schema("No Name".to_string())),
             },
             cells: JsonValue::Null,
         }
     }
 
+    /// Serialize an item into a row of its own type's template
+    ///
+    /// This builds the template each time, so use RowTemplate::serialize when writing many items
+    pub fn from_item<T: SubparRow + serde::Serialize>(item: &T) -> Result<Row> {
+        T::get_template().serialize(item)
+    }
+
     /// Append a cell to the end of the row
     pub fn add_cell(&mut self, name: &str, cell: serde_json::Value) -> Result<()> {
-        match self.cells.as_object_mut() {
-            Some(root) => {
-                if let Some(old) = root.insert(name.to_string(), cell) {
-                    let _ = root.insert(name.to_string(), old);
-
-                    return Err(err!(
-                        DuplicateKey,
-                        "Attempted to add a second column named '{}'",
-                        name
-                    ));
-                };
+        self.add_cell_at(&[name.to_string()], cell)
+    }
+
+    /// Add a cell inside nested objects, creating the objects along the path as needed
+    pub fn add_cell_at(&mut self, path: &[String], cell: serde_json::Value) -> Result<()> {
+        let (last, parents) = path
+            .split_last()
+            .ok_or_else(|| err!(BadValue, "Cannot add a cell with an empty name"))?;
+
+        let mut current = &mut self.cells;
+        for key in parents {
+            current = Row::as_object(current, path)?
+                .entry(key.clone())
+                .or_insert(JsonValue::Null);
+        }
+
+        let root = Row::as_object(current, path)?;
+        if root.contains_key(last) {
+            return Err(err!(
+                DuplicateKey,
+                "Attempted to add a second column named '{}'",
+                path.join(".")
+            ));
+        }
+        root.insert(last.clone(), cell);
+
+        Ok(())
+    }
+
+    /// Make sure the value is an object so cells can be added to it
+    fn as_object<'a>(
+        value: &'a mut JsonValue,
+        path: &[String],
+    ) -> Result<&'a mut serde_json::Map<String, JsonValue>> {
+        if value.is_null() {
+            *value = JsonValue::Object(serde_json::Map::new());
+        }
+
+        match value {
+            JsonValue::Object(map) => Ok(map),
+            _ => Err(err!(
+                DuplicateKey,
+                "Column '{}' is nested under a cell that already has a value",
+                path.join(".")
+            )),
+        }
+    }
+
+    /// Replace nested objects whose cells are all empty with null, so optional structs become None
+    fn prune_empty(&mut self) {
+        let schema = self.schema.clone();
+        Row::prune_value(&schema, &schema.schema, &mut self.cells);
+    }
+
+    fn prune_value(root: &RootSchema, schema: &SchemaObject, value: &mut JsonValue) {
+        let (properties, map) = match (nested_properties(schema), value) {
+            (Some(properties), JsonValue::Object(map)) => (properties, map),
+            _ => return,
+        };
+
+        for (key, child) in map.iter_mut() {
+            let child_schema = match properties.properties.get(key).and_then(|x| root.resolve(x)) {
+                Some(child_schema) if nested_properties(child_schema).is_some() => child_schema,
+                _ => continue,
+            };
+
+            Row::prune_value(root, child_schema, child);
+            let is_empty = match child {
+                JsonValue::Object(nested) => nested.values().all(JsonValue::is_null),
+                _ => false,
+            };
+            if is_empty {
+                *child = JsonValue::Null;
             }
-            None => {
-                let mut root = serde_json::map::Map::new();
-                root.insert(name.to_string(), cell);
-                self.cells = JsonValue::Object(root);
+        }
+    }
+
+    /// Flatten nested objects back into their dotted column names, as they are written to a sheet
+    pub fn flatten(&self) -> Vec<(String, JsonValue)> {
+        let mut columns = Vec::new();
+        if let JsonValue::Object(map) = &self.cells {
+            for (key, value) in map {
+                let schema = self.schema.get_subschema(key).ok();
+                Row::flatten_value(&self.schema, schema, key.clone(), value, &mut columns);
             }
         }
+        columns
+    }
 
-        Ok(())
+    fn flatten_value(
+        root: &RootSchema,
+        schema: Option<&SchemaObject>,
+        name: String,
+        value: &JsonValue,
+        columns: &mut Vec<(String, JsonValue)>,
+    ) {
+        let properties = match schema.and_then(nested_properties) {
+            Some(properties) => properties,
+            None => return columns.push((name, value.clone())),
+        };
+
+        // A missing optional struct still needs to write out each of its columns
+        for (key, child_schema) in properties.properties.iter() {
+            let child = value.get(key).unwrap_or(&JsonValue::Null);
+            Row::flatten_value(
+                root,
+                root.resolve(child_schema),
+                format!("{}.{}", name, key),
+                child,
+                columns,
+            );
+        }
     }
 
-    /// Retrieve a cell from the row
+    /// Retrieve a cell from the row, using the dotted name for nested cells
     pub fn get_cell(&self, cell_name: &str) -> Result<JsonValue> {
-        let cell = self.cells.get(cell_name).ok_or_else(|| {
-            err!(
-                NotFound,
-                "Could not find cell named '{}' in row. The options are: {:#?}",
-                cell_name,
-                self.cells
-            )
-        })?;
+        let cell = self
+            .cells
+            .get(cell_name)
+            .or_else(|| {
+                cell_name
+                    .split('.')
+                    .try_fold(&self.cells, |value, key| value.get(key))
+            })
+            .ok_or_else(|| {
+                err!(
+                    NotFound,
+                    "Could not find cell named '{}' in row. The options are: {:#?}",
+                    cell_name,
+                    self.cells
+                )
+            })?;
         Ok(cell.clone())
     }
 
@@ -501,12 +1375,14 @@ impl Row {
     }
 
     /// Use serde to convert the cells into type T
+    ///
+    /// This reads the cells in place, so nothing is cloned
     pub fn deserialize<T: serde::de::DeserializeOwned>(&self) -> Result<T>
     where
         T: std::fmt::Debug + serde::de::DeserializeOwned,
     {
         Ok(unwrap!(
-            serde_json::from_value(self.cells.clone()),
+            T::deserialize(&self.cells),
             "Could not deserialize {:?}",
             self.cells
         ))
@@ -523,3 +1399,48 @@ impl Row {
     //   }
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    helpers::test_row!(Person {
+        name: String,
+        email: String,
+    });
+
+    fn headers(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn near_misses_are_suggested() {
+        let report = Person::get_template().check_headers(&headers(&["name", "e-mail"]));
+        assert!(!report.is_valid());
+        assert_eq!(
+            report.missing,
+            vec![MissingHeader {
+                name: "email".to_string(),
+                suggestion: Some("e-mail".to_string()),
+            }]
+        );
+        assert_eq!(report.unexpected, vec!["e-mail".to_string()]);
+    }
+
+    #[test]
+    fn distant_headers_are_not_suggested() {
+        let report = Person::get_template().check_headers(&headers(&["name", "address"]));
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].suggestion, None);
+    }
+
+    #[test]
+    fn duplicates_are_only_reported() {
+        let template = Person::get_template();
+        let headers = headers(&["name", "email", "name"]);
+        let report = template.check_headers(&headers);
+        assert!(report.is_valid());
+        assert_eq!(report.duplicates, vec!["name".to_string()]);
+        template.validate_headers(&headers).unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+//! Serialize structs into rows for writing
+//!
+//! This is the other half of the de module. Each field of the struct becomes a cell of the row,
+//! with nested structs kept as objects until the row is flattened into dotted columns by the writer.
+//! The values stay in their JSON form here. Spelling them as cell text, such as joining arrays or
+//! formatting dates, is left to the writer's cell codec so every backend writes them the same way.
+
+use crate::local::*;
+
+use serde::ser::{self, Impossible, Serialize, SerializeMap, SerializeStruct};
+use serde_json::Value as JsonValue;
+
+/// A serde Serializer that turns a struct or map into a row of the template
+pub struct RowSerializer<'a> {
+  template: &'a RowTemplate,
+}
+
+impl<'a> RowSerializer<'a> {
+  pub fn new(template: &'a RowTemplate) -> RowSerializer<'a> {
+    RowSerializer { template }
+  }
+
+  fn not_a_row(kind: &str) -> SubparError {
+    err!(
+      ConversionError,
+      "Only structs and maps can be written as rows, not {}",
+      kind
+    )
+  }
+}
+
+impl<'a> ser::Serializer for RowSerializer<'a> {
+  type Ok = Row;
+  type Error = SubparError;
+
+  type SerializeSeq = Impossible<Row, SubparError>;
+  type SerializeTuple = Impossible<Row, SubparError>;
+  type SerializeTupleStruct = Impossible<Row, SubparError>;
+  type SerializeTupleVariant = Impossible<Row, SubparError>;
+  type SerializeMap = RowFields<'a>;
+  type SerializeStruct = RowFields<'a>;
+  type SerializeStructVariant = Impossible<Row, SubparError>;
+
+  fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<RowFields<'a>> {
+    Ok(RowFields::new(self.template))
+  }
+
+  fn serialize_map(self, _len: Option<usize>) -> Result<RowFields<'a>> {
+    Ok(RowFields::new(self.template))
+  }
+
+  fn serialize_newtype_struct<T: ?Sized + Serialize>(
+    self,
+    _name: &'static str,
+    value: &T,
+  ) -> Result<Row> {
+    value.serialize(self)
+  }
+
+  fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Row> {
+    value.serialize(self)
+  }
+
+  fn serialize_bool(self, _v: bool) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a bool"))
+  }
+
+  fn serialize_i8(self, _v: i8) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a number"))
+  }
+
+  fn serialize_i16(self, _v: i16) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a number"))
+  }
+
+  fn serialize_i32(self, _v: i32) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a number"))
+  }
+
+  fn serialize_i64(self, _v: i64) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a number"))
+  }
+
+  fn serialize_u8(self, _v: u8) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a number"))
+  }
+
+  fn serialize_u16(self, _v: u16) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a number"))
+  }
+
+  fn serialize_u32(self, _v: u32) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a number"))
+  }
+
+  fn serialize_u64(self, _v: u64) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a number"))
+  }
+
+  fn serialize_f32(self, _v: f32) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a number"))
+  }
+
+  fn serialize_f64(self, _v: f64) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a number"))
+  }
+
+  fn serialize_char(self, _v: char) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a char"))
+  }
+
+  fn serialize_str(self, _v: &str) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a string"))
+  }
+
+  fn serialize_bytes(self, _v: &[u8]) -> Result<Row> {
+    Err(RowSerializer::not_a_row("bytes"))
+  }
+
+  fn serialize_none(self) -> Result<Row> {
+    Err(RowSerializer::not_a_row("None"))
+  }
+
+  fn serialize_unit(self) -> Result<Row> {
+    Err(RowSerializer::not_a_row("a unit"))
+  }
+
+  fn serialize_unit_struct(self, name: &'static str) -> Result<Row> {
+    Err(RowSerializer::not_a_row(name))
+  }
+
+  fn serialize_unit_variant(
+    self,
+    name: &'static str,
+    _index: u32,
+    _variant: &'static str,
+  ) -> Result<Row> {
+    Err(RowSerializer::not_a_row(name))
+  }
+
+  fn serialize_newtype_variant<T: ?Sized + Serialize>(
+    self,
+    name: &'static str,
+    _index: u32,
+    _variant: &'static str,
+    _value: &T,
+  ) -> Result<Row> {
+    Err(RowSerializer::not_a_row(name))
+  }
+
+  fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+    Err(RowSerializer::not_a_row("a sequence"))
+  }
+
+  fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+    Err(RowSerializer::not_a_row("a tuple"))
+  }
+
+  fn serialize_tuple_struct(
+    self,
+    name: &'static str,
+    _len: usize,
+  ) -> Result<Self::SerializeTupleStruct> {
+    Err(RowSerializer::not_a_row(name))
+  }
+
+  fn serialize_tuple_variant(
+    self,
+    name: &'static str,
+    _index: u32,
+    _variant: &'static str,
+    _len: usize,
+  ) -> Result<Self::SerializeTupleVariant> {
+    Err(RowSerializer::not_a_row(name))
+  }
+
+  fn serialize_struct_variant(
+    self,
+    name: &'static str,
+    _index: u32,
+    _variant: &'static str,
+    _len: usize,
+  ) -> Result<Self::SerializeStructVariant> {
+    Err(RowSerializer::not_a_row(name))
+  }
+}
+
+/// Collects the fields of a struct or map into the row's cells
+pub struct RowFields<'a> {
+  template: &'a RowTemplate,
+  row: Row,
+  /// A map key waiting for its value
+  key: Option<String>,
+}
+
+impl<'a> RowFields<'a> {
+  fn new(template: &'a RowTemplate) -> RowFields<'a> {
+    RowFields {
+      template,
+      row: Row::new(Some(template)),
+      key: None,
+    }
+  }
+
+  fn add<T: ?Sized + Serialize>(&mut self, key: &str, value: &T) -> Result<()> {
+    if !self.template.layout().contains(key) {
+      return Err(err!(
+        UnknownColumn,
+        "'{}' is not a column of row template '{}'",
+        key,
+        self.template.name()
+      ));
+    }
+
+    let value = err_into!(
+      serde_json::to_value(value),
+      "Could not serialize the value of '{}'",
+      key
+    )?;
+    self.row.add_cell(key, value)
+  }
+}
+
+impl<'a> SerializeStruct for RowFields<'a> {
+  type Ok = Row;
+  type Error = SubparError;
+
+  fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<()> {
+    self.add(key, value)
+  }
+
+  fn end(self) -> Result<Row> {
+    Ok(self.row)
+  }
+}
+
+impl<'a> SerializeMap for RowFields<'a> {
+  type Ok = Row;
+  type Error = SubparError;
+
+  fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+    match err_into!(serde_json::to_value(key))? {
+      JsonValue::String(key) => {
+        self.key = Some(key);
+        Ok(())
+      }
+      key => Err(err!(
+        ConversionError,
+        "Row columns must be named by strings, not {}",
+        key
+      )),
+    }
+  }
+
+  fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+    match self.key.take() {
+      Some(key) => self.add(&key, value),
+      None => Err(err!(Impossible, "Serde gave a map value before its key")),
+    }
+  }
+
+  fn end(self) -> Result<Row> {
+    Ok(self.row)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::csv::{CsvReader, CsvWriter};
+  use serde::Deserialize;
+  use std::collections::HashMap;
+
+  #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, schemars::JsonSchema)]
+  struct Address {
+    city: String,
+    zip: Option<i64>,
+  }
+
+  helpers::test_row!(Shipment {
+    id: String,
+    weight: Option<f64>,
+    to: Address,
+  });
+
+  #[test]
+  fn items_round_trip_through_a_file() {
+    let shipments = vec![
+      Shipment {
+        id: "S1".to_string(),
+        weight: Some(2.5),
+        to: Address {
+          city: "Oslo".to_string(),
+          zip: Some(150),
+        },
+      },
+      Shipment {
+        id: "S2".to_string(),
+        weight: None,
+        to: Address {
+          city: "Bergen, Norway".to_string(),
+          zip: None,
+        },
+      },
+    ];
+
+    let path = helpers::test_dir().join("shipments.csv");
+    let path = path.to_str().unwrap();
+    CsvWriter::dump(path, &shipments, None).unwrap();
+    let read: Vec<Shipment> = CsvReader::slurp(path, None).unwrap();
+    assert_eq!(read, shipments);
+  }
+
+  #[test]
+  fn only_structs_and_maps_make_rows() {
+    let template = Shipment::get_template();
+    let err = template.serialize(&None::<Shipment>).unwrap_err();
+    assert!(matches!(err.kind(), Kind::ConversionError));
+    assert!(template.serialize(&"S1").is_err());
+  }
+
+  #[test]
+  fn unknown_map_keys_are_rejected() {
+    let template = Shipment::get_template();
+    let mut item = HashMap::new();
+    item.insert("id", "S1");
+    item.insert("color", "red");
+    let err = template.serialize(&item).unwrap_err();
+    assert!(matches!(err.kind(), Kind::UnknownColumn));
+  }
+}
@@ -3,7 +3,6 @@
 //!
 
 use crate::local::*;
-use anyhow::{Context, Result};
 
 use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
@@ -51,19 +50,31 @@ impl Headers {
 
   /// Replace the current list of column names with a new one
   pub fn set_headers(&self, _names: Vec<String>) -> Result<Headers> {
-    unimplemented!("'set_headers' still needs to be implemented")
+    Err(err!(
+      NotImplemented,
+      "'set_headers' still needs to be implemented"
+    ))
   }
 
   pub fn append(&self, _name: String) -> Result<Headers> {
-    unimplemented!("'set_headers' still needs to be implemented")
+    Err(err!(
+      NotImplemented,
+      "'append' still needs to be implemented"
+    ))
   }
 
   pub fn insert(&self, _name: String, _position: usize) -> Result<Headers> {
-    unimplemented!("'insert' still needs to be implemented")
+    Err(err!(
+      NotImplemented,
+      "'insert' still needs to be implemented"
+    ))
   }
 
   pub fn alias(&self, _name: String, _alt: String) -> Result<Headers> {
-    unimplemented!("'insert' still needs to be implemented")
+    Err(err!(
+      NotImplemented,
+      "'alias' still needs to be implemented"
+    ))
   }
 }
 
@@ -87,7 +98,7 @@ impl SheetMetadata {}
 /// This sets the expectation
 #[derive(Debug, Default)]
 pub struct SheetTemplate {
-  headers: Headers,
+  _headers: Headers,
 }
 
 impl std::fmt::Display for SheetTemplate {
@@ -124,13 +135,13 @@ pub struct Sheet {
   /// This is the expectations for the given sheet
   ///
   /// This can either be calculated from the registered templates or created manually
-  base: Option<SheetTemplate>,
+  base: Option<RowTemplate>,
 
   /// Templates that are associated with this sheet and the modes allowed with them
   ///
   /// This is merely a lookup and the template will need to be passed in. All data from the source
   /// is consumed and the untracked data received is thrown out with the row
-  _templates: HashMap<Uuid, HashSet<Mode>>,
+  templates: HashMap<Uuid, HashSet<Mode>>,
 
   /// An amalgam of all the information known about the sheet data
   _metadata: Option<SheetMetadata>,
@@ -155,7 +166,7 @@ impl Sheet {
       name: name.clone(),
       _accessor,
       base: None,
-      _templates: HashMap::new(),
+      templates: HashMap::new(),
       _metadata: None,
     }
   }
@@ -169,7 +180,7 @@ impl Sheet {
   /// Sets up the format of the sheet using a row template.
   ///
   /// This validates that the sheets are compatible if the base is populated, based on the modes
-  pub fn add_template<Row: SubparRow>(&self, modes: Vec<Mode>) -> Result<()> {
+  pub fn add_template<Row: SubparRow>(&mut self, modes: Vec<Mode>) -> Result<()> {
     let template_id = Row::get_id();
     log::debug!(
       "Applying template '{}' to sheet '{}'",
@@ -178,15 +189,48 @@ impl Sheet {
     );
 
     // must have at least one mode allowed
-    if modes.len() == 0 {
-      err_ctx!(
-        SubparError::BadValue,
+    if modes.is_empty() {
+      return Err(err!(
+        BadValue,
         "Sheet templates ({}) must be registered with at least one mode",
         template_id
-      )?;
+      ));
     }
 
-    // Is the template already registered
+    // Reading needs the template to accept everything the base can hold, while writing needs the
+    // base to accept everything the template can produce. Updating reads the rows back first, so
+    // it needs both.
+    if let Some(base) = &self.base {
+      let diff = base.compare(&Row::get_template());
+      for mode in &modes {
+        let problems = match mode {
+          Mode::Read => diff.read_problems(),
+          Mode::Update => {
+            let mut problems = diff.read_problems();
+            for problem in diff.write_problems() {
+              if !problems.iter().any(|found| std::ptr::eq(*found, problem)) {
+                problems.push(problem);
+              }
+            }
+            problems
+          }
+          Mode::Append | Mode::Insert | Mode::Overwrite => diff.write_problems(),
+        };
+        if !problems.is_empty() {
+          return Err(err!(
+            IncompatibleTemplate,
+            "Template {} cannot be used in {:?} mode on sheet '{}': {:?}",
+            template_id,
+            mode,
+            self.name,
+            problems
+          ));
+        }
+      }
+    }
+
+    // Registering a template again adds to its allowed modes
+    self.templates.entry(template_id).or_default().extend(modes);
 
     Ok(())
   }
@@ -201,16 +245,10 @@ pub enum ReaderWrapper {
   // Sheets(SheetsReader),
 }
 
-impl Default for ReaderWrapper {
-  fn default() -> ReaderWrapper {
-    unimplemented!("Default is not implemented for ")
-  }
-}
-
 #[derive(Debug)]
 pub struct Reader {
-  sheet_name: String,
-  state: Rc<std::cell::RefCell<State>>,
+  _sheet_name: String,
+  state: Arc<RwLock<State>>,
   internal: ReaderWrapper,
 }
 
@@ -222,17 +260,19 @@ impl std::fmt::Display for Reader {
 
 impl Reader {
   /// Create a new instance
-  pub fn new() -> Reader {
-    unimplemented!("'Reader::new' is not implemented yet")
+  pub fn new() -> Result<Reader> {
+    Err(err!(NotImplemented, "'Reader::new' is not implemented yet"))
   }
 }
 
 /// Wrap up the internal reader (CSV)
 impl Iterator for Reader {
-  type Item = Row;
+  type Item = Result<Row>;
 
   fn next(&mut self) -> Option<Self::Item> {
-    unimplemented!("'Reader Iterator.next' still needs to be implemented")
+    match &mut self.internal {
+      ReaderWrapper::Csv(reader) => reader.next(),
+    }
   }
 }
 
@@ -246,10 +286,74 @@ macro_rules! is_sheet_modifier {
 
     impl Drop for $ty {
       fn drop(&mut self) {
-        let _ = (*self.state).borrow_mut().close();
+        if let Ok(mut state) = self.state.write() {
+          let _ = state.close();
+        }
       }
     }
   };
 }
 
 is_sheet_modifier! {Reader}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::helpers::test_row;
+
+  test_row!(Counts {
+    name: String,
+    count: i64
+  });
+  test_row!(Amounts {
+    name: String,
+    count: f64
+  });
+
+  fn sheet_of<T: SubparRow>() -> Sheet {
+    let mut sheet = Sheet::new(&"Stock".to_string(), None);
+    sheet.set_base::<T>().unwrap();
+    sheet
+  }
+
+  fn kind_of(result: Result<()>) -> Option<String> {
+    result.err().map(|err| format!("{:?}", err.kind()))
+  }
+
+  #[test]
+  fn wider_types_cannot_write() {
+    // Numbers with fractions don't fit in the sheet's integer column
+    assert_eq!(
+      kind_of(sheet_of::<Counts>().add_template::<Amounts>(vec![Mode::Append])),
+      Some("IncompatibleTemplate".to_string())
+    );
+    assert!(sheet_of::<Counts>()
+      .add_template::<Amounts>(vec![Mode::Read])
+      .is_ok());
+  }
+
+  #[test]
+  fn narrower_types_cannot_read() {
+    // The sheet's numbers may have fractions an integer can't hold
+    assert_eq!(
+      kind_of(sheet_of::<Amounts>().add_template::<Counts>(vec![Mode::Read])),
+      Some("IncompatibleTemplate".to_string())
+    );
+    assert!(sheet_of::<Amounts>()
+      .add_template::<Counts>(vec![Mode::Append, Mode::Overwrite])
+      .is_ok());
+  }
+
+  #[test]
+  fn updates_need_to_read_and_write() {
+    assert!(sheet_of::<Counts>()
+      .add_template::<Amounts>(vec![Mode::Update])
+      .is_err());
+    assert!(sheet_of::<Amounts>()
+      .add_template::<Counts>(vec![Mode::Update])
+      .is_err());
+    assert!(sheet_of::<Counts>()
+      .add_template::<Counts>(vec![Mode::Update])
+      .is_ok());
+  }
+}
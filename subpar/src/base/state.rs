@@ -4,10 +4,11 @@
 //! and transformations between row and struct
 
 use crate::local::*;
-use anyhow::{Context, Result};
 
 use std::collections::HashMap;
 
+use crate::base::keys::ForeignKey;
+
 /// Whether the current instance is the primary owner of the workbook
 ///
 /// Since this is tabular data, it's going to be treated as brittle. Only handle on instance may
@@ -51,13 +52,16 @@ pub(crate) struct State {
   connection: ConnectionState,
 
   /// The sheet the workbook is currently pointing at
-  active_sheet: Option<String>,
+  _active_sheet: Option<String>,
 
   /// All the known sheets in the workbook, and their underlying metadata (if available)
   sheets: HashMap<String, Box<Sheet>>,
 
   /// Alternate names a sheet can be known by
-  aliases: HashMap<String, String>,
+  _aliases: HashMap<String, String>,
+
+  /// Links between the sheets that are checked by Workbook::validate
+  foreign_keys: Vec<ForeignKey>,
 }
 
 impl std::fmt::Display for State {
@@ -71,9 +75,10 @@ impl State {
     State {
       name,
       connection: ConnectionState::New,
-      active_sheet: None,
+      _active_sheet: None,
       sheets: HashMap::<String, Box<Sheet>>::new(),
-      aliases: HashMap::<String, String>::new(),
+      _aliases: HashMap::<String, String>::new(),
+      foreign_keys: Vec::new(),
     }
   }
 
@@ -85,13 +90,23 @@ impl State {
     // Check the connection is available
     match &self.connection {
       ConnectionState::New | ConnectionState::Closed => (),
-      ConnectionState::Open(active, _) => err_ctx!(
-        SubparError::Busy,
-        "Sheet {} cannot be opened because sheet {} is already active",
-        sheet_name,
-        active
-      )?,
-      _ => unimplemented!("'State::open' still have not implemented all states"),
+      ConnectionState::Open(active, _) => {
+        return Err(err!(
+          Busy,
+          "Sheet {} cannot be opened because sheet {} is already active",
+          sheet_name,
+          active
+        ))
+      }
+      state => {
+        return Err(err!(
+          Busy,
+          "Sheet {} cannot be opened while workbook {} is {:?}",
+          sheet_name,
+          self.name,
+          state
+        ))
+      }
     }
 
     let _sheet = self.get_sheet(sheet_name)?;
@@ -114,10 +129,12 @@ impl State {
   pub fn close(&mut self) -> Result<()> {
     match self.connection {
       ConnectionState::Open(_, _) => self.connection = ConnectionState::Closed,
-      _ => err_ctx!(
-        SubparError::Impossible,
-        "State tried to close the active sheet, but none were open"
-      )?,
+      _ => {
+        return Err(err!(
+          Impossible,
+          "State tried to close the active sheet, but none were open"
+        ))
+      }
     }
     Ok(())
   }
@@ -134,10 +151,12 @@ impl State {
       Box::new(sheet.unwrap_or(Sheet::new(&name, None))),
     ) {
       None => Ok(()),
-      Some(_) => Err(SubparError::DuplicateKey).ctx(format!(
+      Some(_) => Err(err!(
+        DuplicateKey,
         "A sheet with the name {} already exists in the workbook {}",
-        name, self.name,
-      ))?,
+        name,
+        self.name
+      )),
     }
   }
 
@@ -145,21 +164,47 @@ impl State {
   ///
   /// This gets a reference to the contents of the RC. If not found
   fn get_sheet(&mut self, sheet_name: &String) -> Result<&Box<Sheet>> {
-    let sheet = self
+    self
       .sheets
       .get(sheet_name)
-      .ok_or(SubparError::NotFound)
-      .ctx(format!("Could not find sheet '{}'", sheet_name))?;
-    Ok(sheet)
+      .ok_or_else(|| err!(NotFound, "Could not find sheet '{}'", sheet_name))
+  }
+
+  /// Link a column in one sheet to a column in another
+  pub fn add_foreign_key(&mut self, foreign_key: ForeignKey) -> Result<()> {
+    self.get_sheet(&foreign_key.sheet)?;
+    self.get_sheet(&foreign_key.ref_sheet)?;
+
+    if self.foreign_keys.contains(&foreign_key) {
+      return Err(err!(
+        DuplicateKey,
+        "The foreign key {} is already registered in workbook {}",
+        foreign_key,
+        self.name
+      ));
+    }
+    self.foreign_keys.push(foreign_key);
+    Ok(())
+  }
+
+  pub fn foreign_keys(&self) -> &[ForeignKey] {
+    &self.foreign_keys
+  }
+
+  fn get_sheet_mut(&mut self, sheet_name: &String) -> Result<&mut Box<Sheet>> {
+    self
+      .sheets
+      .get_mut(sheet_name)
+      .ok_or_else(|| err!(NotFound, "Could not find sheet '{}'", sheet_name))
   }
 
   /// Apply a template to a sheet
-  pub fn _add_template<Row: SubparRow>(
+  pub fn add_template<Row: SubparRow>(
     &mut self,
     sheet_name: &String,
     modes: Vec<Mode>,
   ) -> Result<()> {
-    let sheet = self.get_sheet(sheet_name)?;
+    let sheet = self.get_sheet_mut(sheet_name)?;
 
     sheet.add_template::<Row>(modes)
   }
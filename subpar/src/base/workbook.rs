@@ -3,16 +3,19 @@
 //! A workbook contains one or more sheets.
 
 use crate::local::*;
-use anyhow::{Context, Result};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::sync::RwLock;
+
+use crate::base::keys::{DanglingReference, ForeignKey};
+
+use schemars::schema::{InstanceType, Schema, SchemaObject, SingleOrVec};
+use serde_json::Value as JsonValue;
 
 /// Mutably borrow the state or sheet
 macro_rules! borrow {
   ($item:expr) => {
-    $item.read().or(Err(SubparError::RwLockError))?
+    $item.read().or(Err(err!(RwLockError)))?
   };
   ($var:ident, $item:expr) => {
     let $var = borrow!($item);
@@ -21,7 +24,7 @@ macro_rules! borrow {
     borrow!($var, $item)
   };
   ("w", $var:ident, $item:expr) => {
-    let mut $var = $item.write().or(Err(SubparError::RwLockError))?;
+    let mut $var = $item.write().or(Err(err!(RwLockError)))?;
   };
 }
 
@@ -33,22 +36,25 @@ pub enum BuildParams<'a> {
   // GoogleSheets,
   CSV(&'a str),
   /// A premade instance that needs to be wrapped
-  Built(Rc<dyn SubparWorkbook>),
+  Built(Arc<dyn SubparWorkbook>),
 }
 
 /// A meta-workbook, wrapping the different readers/writers
 #[derive(Debug)]
 pub struct Workbook {
-  guid: Uuid,
+  _guid: Uuid,
 
   /// Sheet objects organized by a pretty name
-  sheets: HashMap<String, Rc<Sheet>>,
+  _sheets: HashMap<String, Arc<Sheet>>,
 
   /// A typed workbook configuration
-  instance: Rc<dyn SubparWorkbook>,
+  instance: Arc<dyn SubparWorkbook>,
 
   /// Management info of the included sheets and facilitator of intra-workbook communication
   state: RwLock<State>,
+
+  /// Text read as an empty cell in every sheet, on top of each template's own null values
+  null_values: Vec<String>,
 }
 
 impl std::fmt::Display for Workbook {
@@ -61,17 +67,18 @@ impl Workbook {
   /// Create a new workbook
   pub fn new(params: BuildParams) -> Result<Workbook> {
     let instance = match params {
-      BuildParams::CSV(path) => Rc::new(csv::CsvWorkbook::new(path)?),
+      BuildParams::CSV(path) => Arc::new(csv::CsvWorkbook::new(path)?),
       BuildParams::Built(instance) => instance,
     };
 
     let state = State::new(instance.get_name()?);
 
     let mut wb = Workbook {
-      guid: instance.get_id()?,
+      _guid: instance.get_id()?,
       instance: instance.to_owned(),
-      sheets: HashMap::new(),
+      _sheets: HashMap::new(),
       state: RwLock::new(state),
+      null_values: Vec::new(),
     };
 
     wb.init()?;
@@ -87,15 +94,11 @@ impl Workbook {
     let inst: &dyn SubparWorkbook = self.instance.borrow();
 
     // Get the first glance at the sheets, no deep scans
-    let (_, err) = ErrorGroup::unwrap_all(
-      inst
-        .list_sheets()?
-        .iter()
-        .map(|sheet| state.add_sheet(sheet.to_owned(), None)),
-    );
-    err
-      .map(|errors| Err(SubparError::to_group(errors)).ctx("Failed to add all the sheets"))
-      .unwrap_or(Ok(()))?;
+    for sheet in inst.list_sheets()? {
+      state
+        .add_sheet(sheet, None)
+        .context("Failed to add all the sheets")?;
+    }
 
     Ok(())
   }
@@ -115,61 +118,209 @@ impl Workbook {
   ///
   /// THINK: Should this return rows or the actual split result? I lean toward the latter as I
   /// usually want to do some post-processing after reading, even if some errors are acceptable
-  pub fn slurp<Row: SubparRow>(&mut self, sheet_name: &String) -> Result<SplitResult<Row>> {
+  pub fn slurp<T>(&mut self, sheet_name: &String) -> Result<SplitResult<T>>
+  where
+    T: SubparRow + TryFrom<Row, Error = SubparError>,
+  {
     log::debug!("Starting to slurp {}", sheet_name);
 
     borrow!("w", state, self.state);
     state.open(sheet_name, Mode::Read)?;
 
     // Map the row type to the sheet name
-    // state.add_template::<Row>(sheet_name, vec![Mode::Read])?;
+    // state.add_template::<T>(sheet_name, vec![Mode::Read])?;
 
     // Open the sheet in read mode
-    let accessor = self.instance.get_sheet_accessor(sheet_name)?;
-    let reader = CsvReader::slurp(accessor, None)?;
-
-    Ok(SplitResult::map(reader, |line| {
-      // log::debug!("Processing Row: {:#?}", line);
-      match line {
-        Ok(row) => {
-          let row: Result<Row> = TryFrom::try_from(row);
-          // log::debug!("Converted to: {:#?}", row);
-          row
+    let SheetAccessor::Csv(path) = self.instance.get_sheet_accessor(sheet_name)?;
+    let rows = CsvReader::new(
+      Accessor::Csv(path),
+      Some(Arc::new(T::get_template())),
+      Some(self.read_options()),
+    )
+    .map(|reader| {
+      SplitResult::map(reader, |line| {
+        // log::debug!("Processing Row: {:#?}", line);
+        match line {
+          Ok(row) => {
+            let row: Result<T> = TryFrom::try_from(row);
+            // log::debug!("Converted to: {:#?}", row);
+            row
+          }
+          Err(err) => Err(err),
         }
-        Err(err) => Err(err),
+      })
+    });
+
+    state.close()?;
+    rows
+  }
+
+  /// Visit the named columns of each row of a sheet, numbered by record
+  ///
+  /// Only those columns are converted, all as strings so values from different sheets compare the
+  /// same way. The rows are dropped once visited, so the sheet is never held in memory.
+  fn scan_columns<F>(&mut self, sheet_name: &String, columns: &[String], mut visit: F) -> Result<()>
+  where
+    F: FnMut(i64, &Row) -> Result<()>,
+  {
+    borrow!("w", state, self.state);
+    state.open(sheet_name, Mode::Read)?;
+
+    let mut root = RowTemplate::blank_schema(sheet_name.to_string());
+    if let Some(object) = root.schema.object.as_mut() {
+      for column in columns {
+        object.properties.insert(
+          column.to_string(),
+          Schema::Object(SchemaObject {
+            instance_type: Some(SingleOrVec::Single(Box::new(InstanceType::String))),
+            ..SchemaObject::default()
+          }),
+        );
       }
-    }))
+    }
+    let template = RowTemplate::new(sheet_name.to_string(), Some(root));
+
+    let SheetAccessor::Csv(path) = self.instance.get_sheet_accessor(sheet_name)?;
+    let scanned = CsvReader::new(
+      Accessor::Csv(path),
+      Some(Arc::new(template)),
+      Some(self.read_options()),
+    )
+    .and_then(|reader| {
+      reader
+        .enumerate()
+        .try_for_each(|(i, row)| visit(i as i64 + 1, &row?))
+    });
+
+    state.close()?;
+    scanned
+  }
+
+  /// Register a row type with a sheet for the given modes
+  ///
+  /// This fails if the sheet has a base template the row type can't safely read or write
+  pub fn add_template<T: SubparRow>(
+    &mut self,
+    sheet_name: &String,
+    modes: Vec<Mode>,
+  ) -> Result<()> {
+    borrow!("w", state, self.state);
+    state.add_template::<T>(sheet_name, modes)
+  }
+
+  /// Set the text read as an empty cell in every sheet, such as "N/A" or "#N/A"
+  pub fn set_null_values(&mut self, values: &[&str]) {
+    self.null_values = values.iter().map(|x| x.to_string()).collect();
+  }
+
+  /// The options for every reader the workbook opens
+  fn read_options(&self) -> csv::io::reader::Options {
+    csv::io::reader::Options {
+      null_values: self.null_values.clone(),
+      ..Default::default()
+    }
+  }
+
+  /// Declare that a column references another sheet, written as "Orders.customer_id -> Customers.id"
+  pub fn add_foreign_key(&mut self, link: &str) -> Result<()> {
+    let foreign_key = ForeignKey::parse(link)?;
+    borrow!("w", state, self.state);
+    state.add_foreign_key(foreign_key)
+  }
+
+  /// Check every foreign key in the workbook, returning all the references that don't match a row
+  ///
+  /// Each involved sheet is only read once, no matter how many keys use it, and only the values of
+  /// the key columns are kept
+  pub fn validate(&mut self) -> Result<Vec<DanglingReference>> {
+    let foreign_keys = borrow!(self.state).foreign_keys().to_vec();
+
+    // The columns read from each sheet, whether it is referencing or referenced
+    let mut sheets: HashMap<String, Vec<String>> = HashMap::new();
+    for foreign_key in &foreign_keys {
+      sheets
+        .entry(foreign_key.sheet.clone())
+        .or_default()
+        .extend(foreign_key.columns.iter().cloned());
+      sheets
+        .entry(foreign_key.ref_sheet.clone())
+        .or_default()
+        .extend(foreign_key.ref_columns.iter().cloned());
+    }
+
+    // Indexed the same as the foreign keys
+    let mut references: Vec<Vec<(i64, Vec<JsonValue>)>> = vec![Vec::new(); foreign_keys.len()];
+    let mut known: Vec<HashSet<String>> = vec![HashSet::new(); foreign_keys.len()];
+    for (name, mut columns) in sheets {
+      columns.sort();
+      columns.dedup();
+      self
+        .scan_columns(&name, &columns, |line, row| {
+          for (i, foreign_key) in foreign_keys.iter().enumerate() {
+            if foreign_key.ref_sheet == name {
+              if let Some(values) = foreign_key.extract_referenced(row) {
+                known[i].insert(ForeignKey::key_text(&values)?);
+              }
+            }
+            if foreign_key.sheet == name {
+              if let Some(values) = foreign_key.extract(row) {
+                references[i].push((line, values));
+              }
+            }
+          }
+          Ok(())
+        })
+        .context(format!(
+          "Could not read sheet '{}' to validate its foreign keys",
+          name
+        ))?;
+    }
+
+    let mut dangling = Vec::new();
+    for ((foreign_key, references), known) in foreign_keys.iter().zip(references).zip(&known) {
+      dangling.extend(DanglingReference::find(foreign_key, references, known)?);
+    }
+    Ok(dangling)
   }
 
   /// Write a list of rows to a sheet, replacing the existing data
   ///
   /// This is for quick and dirty writing tables with default options
   pub fn dump<Row: SubparRow>(&mut self, _sheet_name: String, _data: Vec<Row>) -> Result<()> {
-    unimplemented!("'Workbook::dump' is not implemented yet")
+    Err(err!(
+      NotImplemented,
+      "'Workbook::dump' is not implemented yet"
+    ))
   }
 
   /// A simple way read a CSV file
-  pub fn read_csv<Row: SubparRow>(path: &str) -> Result<Vec<Row>> {
+  pub fn read_csv<T>(path: &str) -> Result<Vec<T>>
+  where
+    T: SubparRow + TryFrom<Row, Error = SubparError>,
+  {
     let mut wb = Workbook::new(BuildParams::CSV(path))?;
     log::debug!("New Workbook in read_csv: {:?}", wb);
 
     // Check to make sure there is only one sheet
     let mut sheets = wb.list_sheets()?;
     let sheet_name = match sheets.len() {
-      0 => {
-        Err(SubparError::NotFound).ctx(format!("read_csv could not find any sheets at {}", path))
-      }
+      0 => Err(err!(
+        NotFound,
+        "read_csv could not find any sheets at {}",
+        path
+      )),
       1 => Ok(sheets.pop().unwrap()),
-      _ => Err(SubparError::AmbiguousResult).ctx(format!(
+      _ => Err(err!(
+        AmbiguousResult,
         "read_csv expects one sheet and received multiple for path {}",
-        path,
+        path
       )),
     }?;
 
     log::debug!("Reading the CSV from sheet '{}'", sheet_name);
-    let rows: SplitResult<Row> = wb.slurp::<Row>(&sheet_name)?;
+    let rows: SplitResult<T> = wb.slurp::<T>(&sheet_name)?;
     // log::debug!("Finished reading the rows: {:#?}", rows);
-    let result: Result<Vec<Row>> = rows.as_result();
+    let result: Result<Vec<T>> = rows.as_result();
     Ok(result?)
   }
 }
@@ -238,3 +389,81 @@ pub struct Sheet {
 }
 
 */
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::helpers::test_row;
+
+  test_row!(Price {
+    name: String,
+    price: Option<f64>,
+  });
+
+  #[test]
+  fn null_values_apply_to_every_sheet() {
+    let dir = helpers::test_dir();
+    std::fs::write(dir.join("Prices.csv"), "name,price\nA,N/A\nB,5\n").unwrap();
+    let sheet = "Prices".to_string();
+
+    let mut wb = Workbook::new(BuildParams::CSV(dir.to_str().unwrap())).unwrap();
+    let prices: Result<Vec<Price>> = wb.slurp::<Price>(&sheet).unwrap().as_result();
+    assert!(prices.is_err());
+
+    wb.set_null_values(&["n/a"]);
+    let prices: Result<Vec<Price>> = wb.slurp::<Price>(&sheet).unwrap().as_result();
+    let prices = prices.unwrap();
+    assert_eq!(
+      prices,
+      vec![
+        Price {
+          name: "A".to_string(),
+          price: None
+        },
+        Price {
+          name: "B".to_string(),
+          price: Some(5.0)
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn validate_finds_dangling_references() {
+    let dir = helpers::test_dir();
+    std::fs::write(dir.join("Customers.csv"), "id,name\nC1,Ann\nC2,Bob\n").unwrap();
+    std::fs::write(
+      dir.join("Orders.csv"),
+      "id,customer_id,total\n1,C1,10\n2,C9,20\n3,C2,30\n4,,40\n",
+    )
+    .unwrap();
+
+    let mut wb = Workbook::new(BuildParams::CSV(dir.to_str().unwrap())).unwrap();
+    wb.add_foreign_key("Orders.customer_id -> Customers.id")
+      .unwrap();
+
+    // The empty reference on line 4 doesn't point at anything, so it isn't dangling
+    let dangling = wb.validate().unwrap();
+    assert_eq!(
+      dangling,
+      vec![DanglingReference {
+        sheet: "Orders".to_string(),
+        line: 2,
+        column: "customer_id".to_string(),
+        value: JsonValue::String("C9".to_string()),
+        foreign_key: ForeignKey::parse("Orders.customer_id -> Customers.id").unwrap(),
+      }]
+    );
+  }
+
+  #[test]
+  fn foreign_keys_need_known_sheets() {
+    let dir = helpers::test_dir();
+    std::fs::write(dir.join("Orders.csv"), "id,customer_id\n1,C1\n").unwrap();
+
+    let mut wb = Workbook::new(BuildParams::CSV(dir.to_str().unwrap())).unwrap();
+    assert!(wb
+      .add_foreign_key("Orders.customer_id -> Customers.id")
+      .is_err());
+  }
+}
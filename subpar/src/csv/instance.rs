@@ -1,7 +1,6 @@
 //! Implementation of a CSV backed workbook
 
 use crate::local::*;
-use anyhow::{Context, Result};
 
 use std::collections::HashMap;
 use std::path::PathBuf;
@@ -75,15 +74,22 @@ impl std::fmt::Display for CsvWorkbook {
 }
 
 impl CsvWorkbook {
+  /// Split the path into the workbook's directory and its sheets
+  ///
+  /// A directory holds every CSV file in it, while a file is a workbook of one sheet
   fn parse_path(path: &str) -> Result<(PathBuf, Vec<PathBuf>)> {
     // FIXME: This only works if the directory exists. Either it needs to be added before creating
     //        the workbook, on write, or check harder
-    let abs_path = helpers::canonicalize(path)?;
+    let abs_path = helpers::canonicalize(PathBuf::from(path))?;
     if abs_path.is_dir() {
-      Ok((abs_path, vec![]))
+      let csv_files = list_csv_files(&abs_path)?;
+      Ok((abs_path, csv_files))
     } else {
-      let directory = Box::new(abs_path.parent().unwrap().clone());
-      Ok((directory.to_path_buf(), vec![abs_path]))
+      let directory = abs_path
+        .parent()
+        .ok_or_else(|| err!(InvalidPath, "'{}' is not in a directory", path))?
+        .to_path_buf();
+      Ok((directory, vec![abs_path]))
     }
   }
 
@@ -96,13 +102,13 @@ impl CsvWorkbook {
     let mut sheets = HashMap::new();
     for file in csv_files {
       let key = to_sheet_name(&file)?;
-      match sheets.insert(key.clone().to_string(), file.to_owned()) {
-        None => (),
-        Some(_) => Err(SubparError::DuplicateKey).ctx(format!(
+      if sheets.insert(key.to_string(), file.to_owned()).is_some() {
+        return Err(err!(
+          DuplicateKey,
           "There were two CSV files with the same name '{}' in directory '{}'",
           key,
           path_to_str(&directory)?
-        ))?,
+        ));
       }
     }
 
@@ -146,12 +152,10 @@ impl SubparWorkbook for CsvWorkbook {
   }
 
   fn get_sheet_accessor(&self, sheet_name: &String) -> Result<SheetAccessor> {
-    let path = ok_or!(
-      self.sheets.get(sheet_name),
-      SubparError::NotFound,
-      "Could not get a sheet path for {}",
-      sheet_name
-    )?;
+    let path = self
+      .sheets
+      .get(sheet_name)
+      .ok_or_else(|| err!(NotFound, "Could not get a sheet path for {}", sheet_name))?;
     Ok(SheetAccessor::Csv(path.clone()))
   }
 }
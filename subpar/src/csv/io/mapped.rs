@@ -0,0 +1,176 @@
+//! Parse a CSV file straight out of a memory map
+//!
+//! The whole file is mapped once and the parser works on the mapped bytes, instead of copying the
+//! file through a read buffer a block at a time. Going back to an earlier record is just moving an
+//! offset, so re-reading parts of a big local file is cheap.
+
+use super::reader::{core_builder, FileOptions, StringRecord};
+use crate::local::*;
+
+use std::fs::File;
+use std::path::Path;
+
+use csv_core::{ReadRecordResult, Reader as CoreReader};
+use memmap2::Mmap;
+
+/// The records of a mapped file, read in order from an offset
+pub(crate) struct MappedRecords {
+  /// The contents of the file
+  map: Mmap,
+
+  /// The parser, which keeps its state between calls
+  parser: CoreReader,
+
+  /// The offset of the next record in the file
+  offset: usize,
+
+  /// The unescaped text of the record being parsed
+  fields: Vec<u8>,
+
+  /// Where each field of the record ends in fields
+  ends: Vec<usize>,
+
+  /// The number of fields of the first record, which every record has to match
+  width: Option<usize>,
+}
+
+impl MappedRecords {
+  /// Map the file at the path, failing if it can't be opened
+  ///
+  /// The file must not be changed by anything else while it is mapped. Bytes changing underneath
+  /// the reader make for bad records, and a file getting shorter can crash the process.
+  pub(crate) fn open(path: &Path, options: &FileOptions) -> Result<MappedRecords> {
+    let file = err_into!(
+      File::open(path),
+      "Could not open '{}' for mapping",
+      path.to_string_lossy()
+    )?;
+    // Safety: the map is only read, and the caveat of the file changing is passed on above
+    let map = err_into!(
+      unsafe { Mmap::map(&file) },
+      "Could not map '{}' into memory",
+      path.to_string_lossy()
+    )?;
+
+    Ok(MappedRecords {
+      map,
+      parser: core_builder(options).build(),
+      offset: 0,
+      fields: vec![0; 1024],
+      ends: vec![0; 64],
+      width: None,
+    })
+  }
+
+  /// The offset of the next record in the file
+  pub(crate) fn position(&self) -> u64 {
+    self.offset as u64
+  }
+
+  /// Continue reading from the offset, which has to be the start of a record
+  pub(crate) fn seek(&mut self, offset: u64) -> Result<()> {
+    if offset > self.map.len() as u64 {
+      return Err(err!(
+        BadValue,
+        "Cannot seek to byte {}, past the end of a {} byte file",
+        offset,
+        self.map.len()
+      ));
+    }
+    self.parser.reset();
+    self.offset = offset as usize;
+    Ok(())
+  }
+
+  /// Parse the next record into the buffer, returning false at the end of the file
+  pub(crate) fn read_record(&mut self, record: &mut StringRecord) -> Result<bool> {
+    record.clear();
+    let (mut nout, mut nend) = (0, 0);
+    loop {
+      // An empty input tells the parser the file is over
+      let (result, read, written, ended) = self.parser.read_record(
+        &self.map[self.offset..],
+        &mut self.fields[nout..],
+        &mut self.ends[nend..],
+      );
+      self.offset += read;
+      nout += written;
+      nend += ended;
+
+      match result {
+        ReadRecordResult::InputEmpty => (),
+        ReadRecordResult::OutputFull => self.fields.resize(self.fields.len() * 2, 0),
+        ReadRecordResult::OutputEndsFull => self.ends.resize(self.ends.len() * 2, 0),
+        ReadRecordResult::End => return Ok(false),
+        ReadRecordResult::Record => break,
+      }
+    }
+
+    let mut start = 0;
+    for end in &self.ends[..nend] {
+      let field = match std::str::from_utf8(&self.fields[start..*end]) {
+        Ok(field) => field,
+        Err(_) => {
+          return Err(err!(
+            ParsingError,
+            "Field {} of the record is not valid UTF-8",
+            record.len() + 1
+          ))
+        }
+      };
+      record.push_field(field);
+      start = *end;
+    }
+
+    match self.width {
+      Some(width) if width != record.len() => Err(err!(
+        ParsingError,
+        "The record has {} fields, but the first record had {}",
+        record.len(),
+        width
+      )),
+      Some(_) => Ok(true),
+      None => {
+        self.width = Some(record.len());
+        Ok(true)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::reader::{CsvReader, Options};
+  use super::*;
+  use serde_json::Value as JsonValue;
+
+  #[test]
+  fn mapped_files_use_the_file_options() {
+    let path = helpers::test_dir().join("semicolons.csv");
+    std::fs::write(&path, "name;note\n# skipped\n12\" pipe;'a; b'\n").unwrap();
+
+    let options = |mmap| Options {
+      mmap,
+      file_options: FileOptions {
+        delimiter: b';',
+        quote: b'\'',
+        comment: Some(b'#'),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    let read = |mmap| -> Vec<(JsonValue, JsonValue)> {
+      CsvReader::new(Accessor::Csv(path.clone()), None, Some(options(mmap)))
+        .unwrap()
+        .map(|row| {
+          let row = row.unwrap();
+          (row.get_cell("name").unwrap(), row.get_cell("note").unwrap())
+        })
+        .collect()
+    };
+
+    let expected = vec![(JsonValue::from("12\" pipe"), JsonValue::from("a; b"))];
+    assert_eq!(read(false), expected);
+    assert_eq!(read(true), expected);
+  }
+}
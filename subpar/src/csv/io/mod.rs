@@ -4,3 +4,19 @@
 
 pub mod reader;
 pub use reader::CsvReader;
+
+pub mod writer;
+pub use writer::CsvWriter;
+
+#[cfg(feature = "mmap")]
+mod mapped;
+
+#[cfg(feature = "parallel")]
+pub mod parallel;
+#[cfg(feature = "parallel")]
+pub use parallel::ParallelCsvReader;
+
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(feature = "async")]
+pub use stream::{AsyncCsvReader, AsyncCsvWriter};
@@ -0,0 +1,238 @@
+//! Read large CSV files on a thread pool
+//!
+//! The file is cut into chunks at record boundaries, minding newlines inside quoted cells, and the
+//! chunks are parsed and converted on rayon's pool. Rows still come out in the order of the file
+//! with the record numbers the plain reader would give them, and the template's keys are checked
+//! in that order too.
+
+use super::reader::{CsvReader, Decoded, Decoder, RecordScanner, StringRecord};
+use crate::local::*;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+
+use rayon::prelude::*;
+
+/// The decoded records of a chunk, by their place in it, along with how many records it had
+type Chunk = (i64, Vec<(i64, Result<Decoded>)>);
+
+/// A CsvReader that reads ahead on a thread pool, created with CsvReader::into_parallel
+pub struct ParallelCsvReader {
+  /// The reader doing the converting, which has already read everything before the file position
+  reader: CsvReader,
+
+  /// The rest of the file
+  file: File,
+
+  /// The rough number of bytes in a chunk. A chunk always ends with a whole record.
+  chunk_size: usize,
+
+  /// Bytes read from the file that aren't part of a chunk yet
+  carry: Vec<u8>,
+
+  /// Finds the ends of records in the carry
+  scanner: RecordScanner,
+
+  /// How much of the carry the scanner has been through
+  scanned: usize,
+
+  /// Whether the whole file has been read into chunks
+  eof: bool,
+
+  /// Decoded records waiting for their keys to be checked, by record number
+  ready: VecDeque<(i64, Result<Decoded>)>,
+}
+
+impl std::fmt::Debug for ParallelCsvReader {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("ParallelCsvReader")
+      .field("reader", &self.reader)
+      .field("chunk_size", &self.chunk_size)
+      .field("ready", &self.ready.len())
+      .finish()
+  }
+}
+
+impl ParallelCsvReader {
+  pub(crate) fn new(reader: CsvReader, file: File, chunk_size: usize) -> ParallelCsvReader {
+    ParallelCsvReader {
+      scanner: reader.record_scanner(),
+      reader,
+      file,
+      chunk_size: chunk_size.max(1),
+      carry: Vec::new(),
+      scanned: 0,
+      eof: false,
+      ready: VecDeque::new(),
+    }
+  }
+
+  /// Decode the next batch of chunks, one per thread, returning false once the file is done
+  fn next_batch(&mut self) -> Result<bool> {
+    let mut chunks = Vec::new();
+    while chunks.len() < rayon::current_num_threads() {
+      match self.next_chunk()? {
+        Some(chunk) => chunks.push(chunk),
+        None => break,
+      }
+    }
+    if chunks.is_empty() {
+      return Ok(false);
+    }
+
+    let decoder = self.reader.decoder();
+    let decoded: Vec<Chunk> = chunks
+      .par_iter()
+      .map(|bytes| decode_chunk(&self.reader, decoder, bytes))
+      .collect();
+
+    // Record numbers are only known once the chunks before have been counted
+    let mut line = self.reader.current_line();
+    for (count, records) in decoded {
+      for (i, record) in records {
+        self.ready.push_back((line + i, record));
+      }
+      line += count;
+    }
+    self.reader.set_current_line(line);
+    Ok(true)
+  }
+
+  /// Cut the next run of whole records out of the file
+  fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+    loop {
+      if !self.eof && self.carry.len() < self.chunk_size {
+        let read = err_into!(
+          (&mut self.file)
+            .take(self.chunk_size as u64)
+            .read_to_end(&mut self.carry),
+          "Could not read a chunk of the file"
+        )?;
+        self.eof = read == 0;
+        continue;
+      }
+
+      if self.eof {
+        return Ok(match self.carry.is_empty() {
+          true => None,
+          false => Some(std::mem::take(&mut self.carry)),
+        });
+      }
+
+      // A record bigger than the chunk size keeps reading until it ends
+      let start = self.scanned;
+      self.scanned = self.carry.len();
+      match self.scanner.scan(&self.carry[start..]) {
+        Some(end) => {
+          // The scanner has been through the rest already, and carries on from its end
+          let rest = self.carry.split_off(start + end);
+          self.scanned = rest.len();
+          return Ok(Some(std::mem::replace(&mut self.carry, rest)));
+        }
+        None => {
+          let read = err_into!(
+            (&mut self.file)
+              .take(self.chunk_size as u64)
+              .read_to_end(&mut self.carry),
+            "Could not read a chunk of the file"
+          )?;
+          self.eof = read == 0;
+        }
+      }
+    }
+  }
+}
+
+/// Parse and convert every record of a chunk, numbering them from 1 within it
+fn decode_chunk(reader: &CsvReader, decoder: &Decoder, bytes: &[u8]) -> Chunk {
+  let mut parser = reader.chunk_parser(bytes);
+  let mut record = StringRecord::new();
+  let mut decoded = Vec::new();
+  let mut count = 0;
+
+  loop {
+    let found = parser.read_record(&mut record);
+    count += 1;
+    let result = match found {
+      Ok(false) => {
+        count -= 1;
+        break;
+      }
+      Err(err) => err_into!(Err(err), "Error reading a record"),
+      Ok(true) if record.len() != decoder.width() => Err(err!(
+        ParsingError,
+        "The record has {} fields, but the headers have {}",
+        record.len(),
+        decoder.width()
+      )),
+      Ok(true) if !decoder.passes(&record) => continue,
+      Ok(true) => decoder.decode(&record),
+    };
+    decoded.push((count, result));
+  }
+  (count, decoded)
+}
+
+impl Iterator for ParallelCsvReader {
+  type Item = Result<Row>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      if let Some((line, decoded)) = self.ready.pop_front() {
+        return Some(self.reader.decoder_mut().finish(decoded, line));
+      }
+
+      match self.next_batch() {
+        Ok(true) => (),
+        Ok(false) => return None,
+        Err(err) => {
+          // The rest of the file can't be cut into records reliably after a failed read
+          self.eof = true;
+          self.carry.clear();
+          return Some(Err(err));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::Value as JsonValue;
+
+  /// The cells of each row, for comparing readers
+  fn cells(rows: impl Iterator<Item = Result<Row>>) -> Vec<(JsonValue, JsonValue)> {
+    rows
+      .map(|row| {
+        let row = row.unwrap();
+        (row.get_cell("name").unwrap(), row.get_cell("note").unwrap())
+      })
+      .collect()
+  }
+
+  #[test]
+  fn chunks_end_with_whole_records() {
+    let path = helpers::test_dir().join("pipes.csv");
+    let mut text = String::from("name,note\n");
+    for i in 0..20 {
+      text.push_str(&format!("{}\" pipe,plain\n", i));
+      text.push_str(&format!("elbow {},\"bent, \"\"45\"\"\nsecond line\"\n", i));
+    }
+    std::fs::write(&path, text).unwrap();
+
+    let sequential = CsvReader::new(Accessor::Csv(path.clone()), None, None).unwrap();
+    let expected = cells(sequential);
+    assert_eq!(expected.len(), 40);
+    assert_eq!(expected[0].0, JsonValue::from("0\" pipe"));
+    assert_eq!(expected[1].1, JsonValue::from("bent, \"45\"\nsecond line"));
+
+    // Chunks small enough to be cut inside every kind of cell
+    for chunk_size in [1, 7, 16, 64] {
+      let reader = CsvReader::new(Accessor::Csv(path.clone()), None, None).unwrap();
+      let parallel = reader.into_parallel(chunk_size).unwrap();
+      assert_eq!(cells(parallel), expected, "chunk size {}", chunk_size);
+    }
+  }
+}
@@ -3,16 +3,33 @@
 //! This wraps the csv::Reader into the common subpar model
 //! TODO: Convert this to use Reader::from_reader and create a std::io::Read value
 
+use crate::base::{
+  de::{field_names, RawDeserializer, RawFields},
+  filter::{FieldFilter, Filter},
+  keys::KeyTracker,
+  migration::Manifest,
+  options::matches_any,
+  row::ColumnMap,
+};
 pub use crate::local::*;
 
+use serde_json::Value as JsonValue;
+
 pub use ::csv::{Error as CsvError, Reader, ReaderBuilder, StringRecord};
+
+#[cfg(feature = "mmap")]
+use super::mapped::MappedRecords;
+#[cfg(feature = "parallel")]
+use super::parallel::ParallelCsvReader;
 pub use std::collections::HashMap;
+use std::collections::HashSet;
 pub use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Specific options used for creating the reader/writer
 ///
 /// The are mapped directly from https://docs.rs/csv/1.1.6/csv/struct.ReaderBuilder.html
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FileOptions {
   /// Use Ascii delimited text
   pub is_ascii: bool,
@@ -41,6 +58,16 @@ pub struct FileOptions {
   pub trim: bool,
 }
 
+impl FileOptions {
+  /// The escape byte, which only stands in for doubled quotes when those are turned off
+  fn escape(&self) -> Option<u8> {
+    match self.double_quotes {
+      true => None,
+      false => Some(self.escape),
+    }
+  }
+}
+
 impl Default for FileOptions {
   fn default() -> FileOptions {
     FileOptions {
@@ -68,6 +95,93 @@ pub struct Options {
   // Validation options
   /// Add unknown columns to the row without validation. If false, they are just ignored.
   pub keep_unknown: bool,
+
+  /// The template version the file was written with. If not set, this comes from the file's
+  /// manifest or is detected from the headers.
+  pub version: Option<u32>,
+
+  /// Extra text read as an empty cell in every column, on top of the template's null values
+  pub null_values: Vec<String>,
+
+  /// Only convert these template columns, leaving the rest of each record unread. Every column is
+  /// converted by default.
+  pub columns: Option<Vec<String>>,
+
+  /// Skip the records that fail this, testing their raw text before converting anything
+  pub filter: Filter,
+
+  /// Parse the file straight out of a memory map instead of reading it through a buffer. This is
+  /// for big local files, especially ones that are read more than once with seek or rewind.
+  #[cfg(feature = "mmap")]
+  pub mmap: bool,
+}
+
+/// Where a reader gets its records from
+enum Source {
+  /// Read through a buffer, a block at a time
+  Buffered(Reader<std::fs::File>),
+  /// Parsed from the file's bytes mapped into memory
+  #[cfg(feature = "mmap")]
+  Mapped(Box<MappedRecords>),
+}
+
+impl Source {
+  fn open(path: &std::path::Path, options: &Options) -> Result<Source> {
+    #[cfg(feature = "mmap")]
+    if options.mmap {
+      let records = MappedRecords::open(path, &options.file_options)?;
+      return Ok(Source::Mapped(Box::new(records)));
+    }
+
+    let builder = builder(&options.file_options);
+    Ok(Source::Buffered(err_into!(builder.from_path(path))?))
+  }
+
+  /// The first record, as the names of the columns
+  fn headers(&mut self) -> Result<Vec<String>> {
+    let headers = match self {
+      Source::Buffered(reader) => err_into!(reader.headers())?.clone(),
+      #[cfg(feature = "mmap")]
+      Source::Mapped(records) => {
+        let mut headers = StringRecord::new();
+        if !records.read_record(&mut headers)? {
+          return Err(err!(EmptyWorksheet, "The file doesn't have a header line"));
+        }
+        headers
+      }
+    };
+    Ok(headers.iter().map(|x| x.to_owned()).collect())
+  }
+
+  fn read_record(&mut self, record: &mut StringRecord) -> Result<bool> {
+    match self {
+      Source::Buffered(reader) => err_into!(reader.read_record(record)),
+      #[cfg(feature = "mmap")]
+      Source::Mapped(records) => records.read_record(record),
+    }
+  }
+
+  /// The byte offset of the next record
+  fn position(&self) -> u64 {
+    match self {
+      Source::Buffered(reader) => reader.position().byte(),
+      #[cfg(feature = "mmap")]
+      Source::Mapped(records) => records.position(),
+    }
+  }
+
+  /// Continue reading from the offset, which has to be the start of a record
+  fn seek(&mut self, offset: u64) -> Result<()> {
+    match self {
+      Source::Buffered(reader) => {
+        let mut position = ::csv::Position::new();
+        position.set_byte(offset);
+        err_into!(reader.seek(position))
+      }
+      #[cfg(feature = "mmap")]
+      Source::Mapped(records) => records.seek(offset),
+    }
+  }
 }
 
 /// An open iterator pointing a data stream which returns rows of data
@@ -81,24 +195,39 @@ pub struct CsvReader {
   /// Configuration settings for the reader
   options: Options,
 
-  /// The first line of the file if it has a header row, otherwise a stringified list of column numbers.
-  headers: Vec<String>,
+  /// The parser for the contents of the CSV file
+  reader: Source,
+
+  /// The offset of the first record after the headers, for starting over
+  start: u64,
 
-  /// An iterator that will loop over the contents of CSV file, emitting Row objects
-  reader: Box<dyn Iterator<Item = Result<StringRecord, CsvError>>>,
+  /// The buffer each record is read into, reused for the whole file
+  record: StringRecord,
+
+  /// Turns the file's records into rows
+  decoder: Decoder,
 
   /// A counter pointing to the last line red
   current_line: i64,
 
-  /// Define the expected fields
-  template: Rc<RowTemplate>,
+  /// The keys of the record last lent out by read, which only count once it has made a T
+  lent_keys: Option<Vec<Option<String>>>,
+
+  /// Whether the record last lent out by read made a T
+  lent: AtomicBool,
+
+  /// Every column, kept while none were selected so each type read only converts its own
+  all_columns: Option<ColumnMap>,
+
+  /// The fields of the type the columns were last narrowed to
+  projected: Option<&'static [&'static str]>,
 }
 
 impl std::fmt::Debug for CsvReader {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     f.debug_struct("CsvReader")
       .field("path", &self.path)
-      .field("headers", &self.headers)
+      .field("headers", &self.decoder.headers)
       .field("options", &self.options)
       .field("current_line", &self.current_line)
       .finish()
@@ -115,21 +244,10 @@ impl CsvReader {
   /// Create a new reader
   pub fn new(
     accessor: Accessor,
-    template: Option<Rc<RowTemplate>>,
+    template: Option<Arc<RowTemplate>>,
     opts: Option<Options>,
   ) -> Result<CsvReader> {
-    // Set up the base reader
-    let (options, builder) = match opts {
-      None => (Options::default(), ReaderBuilder::new()),
-      Some(options) => {
-        let mut builder = ReaderBuilder::new();
-        if options.file_options.is_ascii {
-          builder.ascii();
-        };
-
-        (options, builder)
-      }
-    };
+    let options = opts.unwrap_or_default();
 
     // Get the canonicalized path of the location
     let canon = &accessor.canonicalize(false)?;
@@ -137,65 +255,58 @@ impl CsvReader {
     // This type check will be needed later
     // _ => Err(Kind::Impossible).context("Tried to build a CSV Reader with an invalid accessor"),
 
-    let mut reader = err_into!(builder.from_path(path.as_path()))?;
-
-    // Get or create a schema for the file
-    let (template, headers) = match options.file_options.has_headers {
-      true => {
-        let headers = err_into!(reader.headers())?
-          .iter()
-          .map(|x| x.to_owned())
-          .collect();
-        match template {
-          Some(schema) => {
-            schema.validate_headers(&headers).context(format!(
-              "Could not validate the headers for {}",
-              schema.name()
-            ))?;
-            (schema, headers)
-          }
-          None => {
-            let schema = BatchResult::fold(
-              RowTemplate::new(canon.name(), None),
-              headers.iter(),
-              |acc: &mut RowTemplate, item| acc.add_column(item, None, false),
-            )
-            .as_result::<SubparError>()?;
-            (Rc::new(schema), headers)
-          }
-        }
-      }
-      false => match template {
-        Some(schema) => {
-          let headers = schema.get_headers()?;
-          (schema, headers)
-        }
-        None => {
-          return Err(err!(
-            NotImplemented,
-            "Cannot read CSV file '{:?}' because it doesn't have either headers or a template",
-            path.to_str()
-          ))
-        }
-      },
+    let mut reader = Source::open(path, &options)?;
+
+    // An explicit version wins over the manifest, which wins over guessing from the headers
+    let version = match options.version {
+      Some(version) => Some(version),
+      None => Manifest::read(path)?.map(|manifest| manifest.version),
+    };
+
+    let headers = match options.file_options.has_headers {
+      true => Some(
+        reader
+          .headers()
+          .with_context(|| format!("Could not read the headers of {}", path.to_string_lossy()))?,
+      ),
+      false => None,
+    };
+    let start = reader.position();
+    let decoder = Decoder::new(
+      &path.to_string_lossy(),
+      &canon.name(),
+      headers,
+      template,
+      &options,
+      version,
+    )?;
+
+    let all_columns = match options.columns {
+      Some(_) => None,
+      None => Some(decoder.columns.clone()),
     };
 
     Ok(CsvReader {
       path: path.clone(),
-      headers,
       options,
-      reader: Box::new(reader.into_records()),
+      reader,
+      start,
+      record: StringRecord::new(),
+      decoder,
       current_line: 0,
-      template,
+      lent_keys: None,
+      lent: AtomicBool::new(false),
+      all_columns,
+      projected: None,
     })
   }
 
-  pub fn slurp<T: SubparRow>(path: &str, _opts: Option<Options>) -> Result<Vec<T>>
+  pub fn slurp<T: SubparRow>(path: &str, opts: Option<Options>) -> Result<Vec<T>>
   where
     T: TryFrom<Row, Error = SubparError>,
   {
     let accessor = Accessor::new_csv(path);
-    let reader = CsvReader::new(accessor, Some(Rc::new(T::get_template())), None)?;
+    let reader = CsvReader::new(accessor, Some(Arc::new(T::get_template())), opts)?;
 
     SplitResult::map(reader, |line| {
       // log::debug!("Processing Row: {:#?}", line);
@@ -213,45 +324,819 @@ impl CsvReader {
   }
 }
 
-/// Loop through the reader, returning generic rows that can be converted into specific structs
-impl Iterator for CsvReader {
-  type Item = Result<Row>;
+/// A converted record, waiting for its keys to be checked in the order of the file
+pub(crate) struct Decoded {
+  row: Row,
+  /// The key columns, when the row itself might not have them
+  keys: Option<Row>,
+}
 
-  fn next(&mut self) -> Option<Self::Item> {
-    self.current_line += 1;
-    // log::debug!("Trying to read data line {}", self.current_line);
-    let record = match self.reader.next() {
-      Some(Ok(rec)) => rec,
-      None => return None,
-      Some(Err(err)) => {
-        return Some(err_into!(
-          Err(err),
+/// Converts the records of one file into rows of its template
+///
+/// This is everything about reading a file apart from getting its records, so each of the readers
+/// convert records the same way.
+pub(crate) struct Decoder {
+  /// Where the records come from, for error messages
+  source: String,
+
+  /// The first line of the file if it has a header row, otherwise the template's headers
+  headers: Vec<String>,
+
+  /// Define the expected fields
+  template: Arc<RowTemplate>,
+
+  /// The template's columns matched to the file's fields, so records are converted by position
+  columns: ColumnMap,
+
+  /// The tests a record's raw text has to pass to be read
+  filter: FieldFilter,
+
+  /// Extra text read as an empty cell in every column
+  null_values: Vec<String>,
+
+  /// The key values already read, for checking the template's unique columns
+  keys: KeyTracker,
+
+  /// The template version the file was written with. Older rows are migrated before converting.
+  version: u32,
+}
+
+impl Decoder {
+  /// Match the file's headers to the template, making a template from the headers if there isn't
+  /// one
+  ///
+  /// Files without a header row are read in the order of the template's headers. An explicit
+  /// version wins over guessing it from the headers.
+  pub(crate) fn new(
+    source: &str,
+    name: &str,
+    headers: Option<Vec<String>>,
+    template: Option<Arc<RowTemplate>>,
+    options: &Options,
+    version: Option<u32>,
+  ) -> Result<Decoder> {
+    let (template, headers) = match headers {
+      Some(headers) => match template {
+        Some(schema) => {
+          // Old layouts are checked by the template once they are migrated
+          let current = version.unwrap_or_else(|| schema.detect_version(&headers));
+          if current == schema.version() {
+            schema.validate_headers(&headers).context(format!(
+              "Could not validate the headers for {}",
+              schema.name()
+            ))?;
+          }
+          (schema, headers)
+        }
+        None => {
+          // Repeated headers make a single column, which reads the last of them
+          let mut seen = HashSet::new();
+          let schema = BatchResult::fold(
+            RowTemplate::new(name.to_string(), None),
+            headers.iter().filter(|header| seen.insert(header.as_str())),
+            |acc: &mut RowTemplate, item| acc.add_column(item, None, false),
+          )
+          .as_result::<SubparError>()?;
+          (Arc::new(schema), headers)
+        }
+      },
+      None => match template {
+        Some(schema) => {
+          let headers = schema.get_headers()?;
+          (schema, headers)
+        }
+        None => {
+          return Err(err!(
+            NotImplemented,
+            "Cannot read CSV file '{}' because it doesn't have either headers or a template",
+            source
+          ))
+        }
+      },
+    };
+
+    let version = version.unwrap_or_else(|| template.detect_version(&headers));
+    let mut columns = template.map_columns(&headers);
+    if let Some(names) = &options.columns {
+      columns.select(names)?;
+    }
+    let filter = options.filter.resolve(&columns)?;
+
+    Ok(Decoder {
+      source: source.to_string(),
+      headers,
+      template,
+      columns,
+      filter,
+      null_values: options.null_values.clone(),
+      keys: KeyTracker::new(),
+      version,
+    })
+  }
+
+  /// The number of fields every record has to have
+  #[cfg(any(feature = "parallel", feature = "async"))]
+  pub(crate) fn width(&self) -> usize {
+    self.headers.len()
+  }
+
+  /// Where the records come from
+  #[cfg(feature = "async")]
+  pub(crate) fn source(&self) -> &str {
+    &self.source
+  }
+
+  /// Whether the file's records are converted straight into the current layout
+  pub(crate) fn is_current(&self) -> bool {
+    self.version == self.template.version()
+  }
+
+  /// Whether the record passes the reader's filter
+  pub(crate) fn passes(&self, record: &StringRecord) -> bool {
+    self.filter.test(|i| record.get(i))
+  }
+
+  /// Convert a record into a row by position, migrating it first if it has an older layout
+  ///
+  /// This only reads the reader, so the parallel reader can run it on many records at once. The
+  /// keys are checked afterwards by finish, in the order of the file.
+  pub(crate) fn decode(&self, record: &StringRecord) -> Result<Decoded> {
+    if self.is_current() {
+      let values: Vec<CellValue> = record
+        .iter()
+        .enumerate()
+        .map(|(i, text)| match self.columns.is_used(i) {
+          true => self.to_cell_value(i, text),
+          false => CellValue::Empty,
+        })
+        .collect();
+      let row = self.template.to_row_at(&self.columns, &values)?;
+      let keys = self.key_row(Some(&row), |name| self.record_value(record, name))?;
+      return Ok(Decoded { row, keys });
+    }
+
+    let mut cells = self
+      .migrate_record(record)
+      .with_context(|| format!("Could not migrate the record from version {}", self.version))?;
+
+    // Unselected key columns are held back, since the row only gets the selected ones
+    let key_cells: HashMap<String, Cell> = self
+      .key_columns()
+      .filter(|name| !self.columns.is_selected(name))
+      .filter_map(|name| Some((name.clone(), cells.remove(name)?)))
+      .collect();
+    let row = self
+      .template
+      .to_row_selected(cells, &self.columns, |_| false)?;
+    let keys = self.key_row(Some(&row), |name| {
+      self.template.cell_value(name, key_cells.get(name))
+    })?;
+    Ok(Decoded { row, keys })
+  }
+
+  /// Check a decoded record's keys against the ones already read, giving back its row
+  pub(crate) fn finish(&mut self, decoded: Result<Decoded>, line: i64) -> Result<Row> {
+    decoded
+      .and_then(|decoded| {
+        let keys = decoded.keys.as_ref().unwrap_or(&decoded.row);
+        self.keys.check(self.template.keys(), keys, line)?;
+        Ok(decoded.row)
+      })
+      .with_context(|| {
+        format!(
+          "Could not convert record {} from file {} into a row",
+          line, self.source,
+        )
+      })
+  }
+
+  /// The columns of the template's keys
+  fn key_columns(&self) -> impl Iterator<Item = &String> {
+    self.template.keys().iter().flat_map(|key| key.columns())
+  }
+
+  /// Build a row of just the key columns, when the decoded row doesn't have them all
+  ///
+  /// Key columns already on the row are taken from it and the rest are converted, so no key value
+  /// is converted twice.
+  fn key_row<F>(&self, row: Option<&Row>, convert: F) -> Result<Option<Row>>
+  where
+    F: Fn(&str) -> Result<Option<JsonValue>>,
+  {
+    let on_row = |name: &str| row.is_some() && self.columns.is_selected(name);
+    if self.key_columns().all(|name| on_row(name)) {
+      return Ok(None);
+    }
+
+    let mut keys = Row::new(Some(self.template.as_ref()));
+    for name in self.key_columns() {
+      let value = match row {
+        Some(row) if on_row(name) => row.get_cell(name).ok(),
+        _ => convert(name)?,
+      };
+      if let Some(value) = value {
+        keys.add_cell(name, value)?;
+      }
+    }
+    Ok(Some(keys))
+  }
+
+  /// Convert one column of a record with the current layout
+  fn record_value(&self, record: &StringRecord, name: &str) -> Result<Option<JsonValue>> {
+    let cell = self.columns.position(name).and_then(|i| {
+      let text = record.get(i)?;
+      Some(Cell::new(name.to_string(), self.to_cell_value(i, text)))
+    });
+    self.template.cell_value(name, cell.as_ref())
+  }
+
+  /// Read a field into a cell value, turning null sentinels into empty cells
+  fn to_cell_value(&self, position: usize, value: &str) -> CellValue {
+    match self.is_null(position, value) {
+      true => CellValue::Empty,
+      false => CellValue::Raw(value.to_string()),
+    }
+  }
+
+  /// Whether the text is one of the column's or the reader's null sentinels
+  fn is_null(&self, position: usize, value: &str) -> bool {
+    self.columns.is_null(position, value) || matches_any(&self.null_values, value)
+  }
+
+  /// Run a record from an older layout through the template's migrations
+  fn migrate_record(&self, record: &StringRecord) -> Result<HashMap<String, Cell>> {
+    let raw = self
+      .headers
+      .iter()
+      .zip(record.iter())
+      .enumerate()
+      .map(|(i, (name, value))| {
+        let value = match self.to_cell_value(i, value) {
+          CellValue::Raw(x) => JsonValue::String(x),
+          _ => JsonValue::Null,
+        };
+        (name.clone(), value)
+      })
+      .collect();
+
+    match self
+      .template
+      .migrate(self.version, JsonValue::Object(raw))?
+    {
+      JsonValue::Object(migrated) => Ok(
+        migrated
+          .into_iter()
+          .map(|(name, value)| {
+            let val = match value {
+              JsonValue::Null => CellValue::Empty,
+              JsonValue::String(x) if x.is_empty() => CellValue::Empty,
+              JsonValue::String(x) => CellValue::Raw(x),
+              JsonValue::Number(x) => CellValue::Number(x),
+              JsonValue::Bool(x) => CellValue::Bool(x),
+              x => CellValue::Raw(x.to_string()),
+            };
+            (name.clone(), Cell::new(name, val))
+          })
+          .collect(),
+      ),
+      x => Err(err!(
+        ConversionError,
+        "Migrations must return an object of cells, but got {}",
+        x
+      )),
+    }
+  }
+}
+
+impl CsvReader {
+  /// Read the next record passing the filter into the reusable buffer, returning false at the end
+  /// of the file
+  fn read_record(&mut self) -> Result<bool> {
+    self.record_lent_keys();
+    loop {
+      self.current_line += 1;
+      // log::debug!("Trying to read data line {}", self.current_line);
+      let found = self.reader.read_record(&mut self.record).map_err(|err| {
+        err.comment(format!(
           "Error reading record {} from file {}",
           self.current_line,
           self.path.to_string_lossy()
-        ));
+        ))
+      })?;
+
+      if !found || self.decoder.passes(&self.record) {
+        return Ok(found);
+      }
+    }
+  }
+
+  /// Read the next record into T, lending it the text of plain string columns
+  ///
+  /// The record is read into a buffer reused for the whole file, so T can borrow &str and
+  /// Cow<str> fields from it instead of allocating them. That means the item has to be dropped
+  /// before the next read, which is why this can't be an iterator. Every other column is
+  /// converted the same way the other readers convert it.
+  ///
+  /// Unless columns were selected, only the ones T has fields for are converted.
+  pub fn read<'r, T: serde::Deserialize<'r>>(&'r mut self) -> Option<Result<T>> {
+    self.project_for::<T>();
+    match self.read_record() {
+      Ok(true) => (),
+      Ok(false) => return None,
+      Err(err) => return Some(Err(err)),
+    }
+
+    // Migrated records are built fresh, so there's nothing left in the buffer to borrow
+    if !self.decoder.is_current() {
+      return Some(Err(err!(
+        NotImplemented,
+        "Cannot borrow from record {} of file {}, since it was written with version {} of '{}'. \
+        Use deserialize to migrate it instead",
+        self.current_line,
+        self.path.to_string_lossy(),
+        self.decoder.version,
+        self.decoder.template.name()
+      )));
+    }
+
+    // T holds on to the reader, so the keys are only recorded by the next read, if T was made
+    match self.test_keys() {
+      Ok(keys) => self.lent_keys = keys,
+      Err(err) => return Some(Err(err)),
+    }
+    self.lent.store(false, Ordering::Relaxed);
+
+    let reader: &'r CsvReader = self;
+    let fields = Fields { reader };
+    let item = T::deserialize(RawDeserializer::with_layout(
+      &reader.decoder.template,
+      fields,
+      reader.decoder.columns.layout(),
+    ))
+    .with_context(|| {
+      format!(
+        "Could not convert record {} from file {}",
+        reader.current_line,
+        reader.path.to_string_lossy(),
+      )
+    });
+    if item.is_ok() {
+      reader.lent.store(true, Ordering::Relaxed);
+    }
+    Some(item)
+  }
+
+  /// Check the buffered record against the template's keys, converting only the key columns
+  fn test_keys(&self) -> Result<Option<Vec<Option<String>>>> {
+    let decoder = &self.decoder;
+    match decoder.key_row(None, |name| decoder.record_value(&self.record, name))? {
+      Some(row) => decoder
+        .keys
+        .test(decoder.template.keys(), &row, self.current_line)
+        .map(Some),
+      None => Ok(None),
+    }
+  }
+
+  /// Record the keys of the record last lent out by read, if it made a T
+  fn record_lent_keys(&mut self) {
+    if let Some(keys) = self.lent_keys.take() {
+      if self.lent.load(Ordering::Relaxed) {
+        self.decoder.keys.record(keys, self.current_line);
       }
+    }
+  }
+
+  /// Only convert the named template columns, leaving the rest of each record unread
+  ///
+  /// Unselected columns never make it onto a row, even when the template requires them.
+  pub fn select<S: AsRef<str>>(&mut self, names: &[S]) -> Result<()> {
+    // Columns narrowed to a type read before are widened again
+    if let Some(all) = &self.all_columns {
+      self.decoder.columns = all.clone();
+      self.projected = None;
+    }
+    self.decoder.columns.select(names).with_context(|| {
+      format!(
+        "Could not select the columns of {}",
+        self.path.to_string_lossy()
+      )
+    })?;
+    self.all_columns = None;
+    Ok(())
+  }
+
+  /// Only convert the columns T has fields for, unless columns were selected
+  ///
+  /// A field holding a nested struct keeps every column under it. Types that don't name their
+  /// fields, like maps, get every column.
+  fn project_for<'de, T: serde::Deserialize<'de>>(&mut self) {
+    let all = match &self.all_columns {
+      Some(all) => all,
+      None => return,
     };
+    let names = field_names::<T>();
+    if names == self.projected {
+      return;
+    }
 
-    // Create a hashmap of cells to be processed
-    let cells =
-      self
-        .headers
-        .iter()
-        .enumerate()
-        .fold(HashMap::<String, Cell>::new(), |mut acc, (i, name)| {
-          let val = match record.get(i).unwrap() {
-            "" => CellValue::Empty,
-            x => CellValue::Raw(x.to_string()),
-          };
-          acc.insert(name.clone(), Cell::new(name.clone(), val));
-          acc
-        });
-
-    Some(self.template.to_row(cells).context(format!(
-      "Could not convert record {} from file {} into a row",
-      self.current_line,
-      self.path.to_string_lossy(),
-    )))
+    let mut columns = all.clone();
+    if let Some(names) = names {
+      columns.project(names);
+    }
+    self.decoder.columns = columns;
+    self.projected = names;
+  }
+
+  /// Only convert the columns T reads, as listed by its template
+  ///
+  /// read and deserialize already do this for the type they're given, so this is for keeping to
+  /// T's columns while reading rows. Columns of T that this template doesn't have are left for T
+  /// to complain about when it's deserialized.
+  pub fn select_for<T: SubparRow>(&mut self) -> Result<()> {
+    let names: Vec<String> = T::get_template()
+      .get_headers()?
+      .into_iter()
+      .filter(|name| self.decoder.template.get_cell_schema(name).is_ok())
+      .collect();
+    self.select(&names)
+  }
+
+  /// Skip the records that fail the filter, on top of any filters already set
+  ///
+  /// The filter is tested on the raw text of each record, so rejected records are never converted.
+  pub fn add_filter(&mut self, filter: &Filter) -> Result<()> {
+    let resolved = filter
+      .resolve(&self.decoder.columns)
+      .with_context(|| format!("Could not filter {}", self.path.to_string_lossy()))?;
+    self.decoder.filter.extend(resolved);
+    Ok(())
+  }
+
+  /// The offset of the next record in the file, for coming back to it with seek
+  pub fn offset(&self) -> u64 {
+    self.reader.position()
+  }
+
+  /// Go to a record by its offset, given by offset, along with the number of the record before it
+  ///
+  /// The key values read so far are forgotten, since reading records again would clash with them.
+  /// This is cheap when the file is mapped.
+  pub fn seek(&mut self, offset: u64, line: i64) -> Result<()> {
+    self
+      .reader
+      .seek(offset)
+      .with_context(|| format!("Could not seek in {}", self.path.to_string_lossy()))?;
+    self.current_line = line;
+    self.decoder.keys = KeyTracker::new();
+    self.lent_keys = None;
+    Ok(())
+  }
+
+  /// Go back to the first record after the headers, to read the file again
+  pub fn rewind(&mut self) -> Result<()> {
+    self.seek(self.start, 0)
+  }
+
+  /// Read the rest of the file on rayon's thread pool, in chunks of about chunk_size bytes
+  #[cfg(feature = "parallel")]
+  pub fn into_parallel(mut self, chunk_size: usize) -> Result<ParallelCsvReader> {
+    use std::io::{Seek, SeekFrom};
+
+    self.record_lent_keys();
+    let mut file = err_into!(
+      std::fs::File::open(&self.path),
+      "Could not reopen {} for reading in parallel",
+      self.path.to_string_lossy()
+    )?;
+    err_into!(file.seek(SeekFrom::Start(self.reader.position())))?;
+    Ok(ParallelCsvReader::new(self, file, chunk_size))
+  }
+
+  /// A parser for a run of records from the middle of the file, set up like this reader's
+  #[cfg(feature = "parallel")]
+  pub(crate) fn chunk_parser<'a>(&self, bytes: &'a [u8]) -> Reader<&'a [u8]> {
+    record_parser(&self.options.file_options, bytes)
+  }
+
+  /// Finds the ends of the records chunks are cut at
+  #[cfg(feature = "parallel")]
+  pub(crate) fn record_scanner(&self) -> RecordScanner {
+    RecordScanner::new(&self.options.file_options)
+  }
+
+  #[cfg(feature = "parallel")]
+  pub(crate) fn decoder(&self) -> &Decoder {
+    &self.decoder
+  }
+
+  #[cfg(feature = "parallel")]
+  pub(crate) fn decoder_mut(&mut self) -> &mut Decoder {
+    &mut self.decoder
+  }
+
+  #[cfg(feature = "parallel")]
+  /// The number of the last record read
+  pub(crate) fn current_line(&self) -> i64 {
+    self.current_line
+  }
+
+  #[cfg(feature = "parallel")]
+  pub(crate) fn set_current_line(&mut self, line: i64) {
+    self.current_line = line;
+  }
+
+  /// Deserialize each record straight into T, skipping the intermediate rows
+  ///
+  /// Files written with an older version of the template still read through rows, since the
+  /// migrations work on whole records
+  ///
+  /// Unless columns were selected, only the ones T has fields for are converted.
+  pub fn deserialize<T>(mut self) -> Records<T>
+  where
+    T: serde::de::DeserializeOwned + std::fmt::Debug,
+  {
+    self.project_for::<T>();
+    Records {
+      reader: self,
+      _type: std::marker::PhantomData,
+    }
+  }
+}
+
+/// A parser for a run of whole records, set up with the file options
+#[cfg(any(feature = "parallel", feature = "async"))]
+pub(crate) fn record_parser<'a>(options: &FileOptions, bytes: &'a [u8]) -> Reader<&'a [u8]> {
+  let mut builder = builder(options);
+  builder.has_headers(false).flexible(true);
+  builder.from_reader(bytes)
+}
+
+/// A CSV reader set up with the file options
+pub(crate) fn builder(options: &FileOptions) -> ReaderBuilder {
+  let mut builder = ReaderBuilder::new();
+  builder
+    .delimiter(options.delimiter)
+    .quote(options.quote)
+    .quoting(options.quoting)
+    .double_quote(options.double_quotes)
+    .escape(options.escape())
+    .comment(options.comment)
+    .flexible(options.flexible)
+    .has_headers(options.has_headers)
+    .terminator(match options.terminator {
+      Some(byte) => ::csv::Terminator::Any(byte),
+      None => ::csv::Terminator::CRLF,
+    });
+  if options.is_ascii {
+    builder.ascii();
+  }
+  builder
+}
+
+/// The bare parser under the CSV reader, set up the same way
+#[cfg(any(feature = "mmap", feature = "parallel", feature = "async"))]
+pub(crate) fn core_builder(options: &FileOptions) -> csv_core::ReaderBuilder {
+  let mut builder = csv_core::ReaderBuilder::new();
+  builder
+    .delimiter(options.delimiter)
+    .quote(options.quote)
+    .quoting(options.quoting)
+    .double_quote(options.double_quotes)
+    .escape(options.escape())
+    .comment(options.comment)
+    .terminator(match options.terminator {
+      Some(byte) => csv_core::Terminator::Any(byte),
+      None => csv_core::Terminator::CRLF,
+    });
+  if options.is_ascii {
+    builder.ascii();
+  }
+  builder
+}
+
+/// Finds the ends of records in bytes that arrive a piece at a time
+///
+/// The records are parsed with the same settings as the reader's, so a quote in the middle of a
+/// cell or an escaped one can't be mistaken for the edge of a quoted cell.
+#[cfg(any(feature = "parallel", feature = "async"))]
+pub(crate) struct RecordScanner {
+  parser: csv_core::Reader,
+  /// Room for the cells, which are only parsed to find where they end
+  output: Vec<u8>,
+  ends: Vec<usize>,
+}
+
+#[cfg(any(feature = "parallel", feature = "async"))]
+impl RecordScanner {
+  pub(crate) fn new(options: &FileOptions) -> RecordScanner {
+    RecordScanner {
+      parser: core_builder(options).build(),
+      output: vec![0; 1024],
+      ends: vec![0; 64],
+    }
+  }
+
+  /// The position just past the last whole record in the next piece
+  ///
+  /// Each piece carries on from the end of the last one, and the first starts a record.
+  pub(crate) fn scan(&mut self, bytes: &[u8]) -> Option<usize> {
+    let mut boundary = None;
+    let mut offset = 0;
+    // No input tells the parser the data is over, so an empty piece is left alone
+    while offset < bytes.len() {
+      let (result, read, _, _) =
+        self
+          .parser
+          .read_record(&bytes[offset..], &mut self.output, &mut self.ends);
+      offset += read;
+      match result {
+        csv_core::ReadRecordResult::Record => boundary = Some(offset),
+        // The cells aren't kept, so the same space is written over
+        csv_core::ReadRecordResult::OutputFull | csv_core::ReadRecordResult::OutputEndsFull => (),
+        csv_core::ReadRecordResult::InputEmpty | csv_core::ReadRecordResult::End => break,
+      }
+    }
+    boundary
+  }
+}
+
+/// The buffered record of a reader, found by header
+#[derive(Clone, Copy)]
+struct Fields<'r> {
+  reader: &'r CsvReader,
+}
+
+impl<'r> RawFields<'r> for Fields<'r> {
+  fn text(&self, name: &str) -> Option<&'r str> {
+    let reader = self.reader;
+    reader
+      .decoder
+      .columns
+      .position(name)
+      .and_then(|i| reader.record.get(i))
+  }
+
+  fn is_null(&self, name: &str, text: &str) -> bool {
+    let decoder = &self.reader.decoder;
+    match decoder.columns.position(name) {
+      Some(i) => decoder.is_null(i, text),
+      None => true,
+    }
+  }
+}
+
+/// An iterator deserializing each record of a CsvReader into T
+pub struct Records<T> {
+  reader: CsvReader,
+  _type: std::marker::PhantomData<T>,
+}
+
+impl<T> Iterator for Records<T>
+where
+  T: serde::de::DeserializeOwned + std::fmt::Debug,
+{
+  type Item = Result<T>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    match self.reader.decoder.is_current() {
+      // T owns its data, so it never holds on to the borrowed record
+      true => self.reader.read(),
+      // Older layouts are migrated into rows first
+      false => self.reader.next().map(|row| row?.deserialize()),
+    }
+  }
+}
+
+/// Loop through the reader, returning generic rows that can be converted into specific structs
+impl Iterator for CsvReader {
+  type Item = Result<Row>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    match self.read_record() {
+      Ok(true) => (),
+      Ok(false) => return None,
+      Err(err) => return Some(Err(err)),
+    }
+
+    let decoded = self.decoder.decode(&self.record);
+    let row = self.decoder.finish(decoded, self.current_line);
+    Some(row)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde::{Deserialize, Serialize};
+  use std::sync::atomic::AtomicUsize;
+
+  helpers::test_row!(Part {
+    id: String,
+    count: i64
+  });
+
+  /// A reader of the text, with the template keyed by id
+  fn part_reader(text: &str, template: RowTemplate) -> CsvReader {
+    let path = helpers::test_dir().join("parts.csv");
+    std::fs::write(&path, text).unwrap();
+    CsvReader::new(Accessor::Csv(path), Some(Arc::new(template)), None).unwrap()
+  }
+
+  fn keyed() -> RowTemplate {
+    let mut template = Part::get_template();
+    template.set_primary_key(&["id"]).unwrap();
+    template
+  }
+
+  #[test]
+  fn headers_make_the_template_when_there_is_none() {
+    let path = helpers::test_dir().join("plain.csv");
+    std::fs::write(&path, "a,b,a\n1,2,3\n").unwrap();
+
+    let reader = CsvReader::new(Accessor::Csv(path), None, None).unwrap();
+    let rows: Vec<Row> = reader.collect::<Result<_>>().unwrap();
+
+    // The last of the repeated columns is the one read
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].get_cell("a").unwrap(), JsonValue::from("3"));
+    assert_eq!(rows[0].get_cell("b").unwrap(), JsonValue::from("2"));
+  }
+
+  #[test]
+  fn key_defaults_are_computed_once() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut template = keyed();
+    let counter = calls.clone();
+    template
+      .add_default("id", move || {
+        let call = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        JsonValue::from(format!("P{}", call))
+      })
+      .unwrap();
+
+    let reader = part_reader("id,count\n,1\n,2\n", template);
+    let parts: Vec<Part> = reader
+      .map(|row| row.and_then(Part::try_from))
+      .collect::<Result<_>>()
+      .unwrap();
+
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert_eq!(parts[0].id, "P1");
+    assert_eq!(parts[1].id, "P2");
+  }
+
+  #[test]
+  fn unselected_keys_are_still_checked() {
+    let mut reader = part_reader("id,count\nA,1\nA,2\n", keyed());
+    reader.select(&["count"]).unwrap();
+
+    let first = reader.next().unwrap().unwrap();
+    assert!(first.get_cell("id").is_err());
+    let second = reader.next().unwrap().unwrap_err();
+    assert!(matches!(second.kind(), Kind::DuplicateKey));
+  }
+
+  #[test]
+  fn borrowed_reads_only_keep_the_keys_of_good_records() {
+    #[derive(Debug, Deserialize)]
+    struct Lent<'a> {
+      id: &'a str,
+      count: i64,
+    }
+
+    let mut reader = part_reader("id,count\nA,x\nA,2\nA,3\n", keyed());
+    assert!(reader.read::<Lent>().unwrap().is_err());
+    let lent = reader.read::<Lent>().unwrap().unwrap();
+    assert_eq!((lent.id, lent.count), ("A", 2));
+    let err = reader.read::<Lent>().unwrap().unwrap_err();
+    assert!(matches!(err.kind(), Kind::DuplicateKey));
+  }
+
+  #[test]
+  fn types_only_read_their_own_columns() {
+    #[derive(Debug, Deserialize)]
+    struct Id {
+      id: String,
+    }
+
+    // The counts don't convert, but Id never asks for them
+    let text = "id,count\nA,x\nB,y\n";
+    let ids: Vec<Id> = part_reader(text, keyed())
+      .deserialize()
+      .collect::<Result<_>>()
+      .unwrap();
+    assert_eq!(ids.len(), 2);
+    assert_eq!(ids[1].id, "B");
+
+    let mut reader = part_reader(text, keyed());
+    assert_eq!(reader.read::<Id>().unwrap().unwrap().id, "A");
+    // A type with more fields gets them back
+    assert!(reader.read::<Part>().unwrap().is_err());
+
+    // Selecting columns turns it off
+    let mut reader = part_reader(text, keyed());
+    reader.select(&["id", "count"]).unwrap();
+    assert!(reader.read::<Id>().unwrap().is_err());
   }
 }
@@ -0,0 +1,464 @@
+//! Read and write CSV over async IO
+//!
+//! The reader is a stream of rows over anything AsyncRead, and the writer is a sink of rows over
+//! anything AsyncWrite. Records are converted with the same template logic as CsvReader and
+//! CsvWriter, only the bytes come and go without blocking.
+
+use super::reader::{self, record_parser, CsvError, Decoder, RecordScanner, StringRecord};
+use super::writer::{self, Writer};
+use crate::local::*;
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{ready, Sink, SinkExt, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The number of bytes asked of the source at a time
+const READ_SIZE: usize = 8 * 1024;
+
+/// The number of encoded bytes the writer holds before it has to write them out
+const WRITE_SIZE: usize = 8 * 1024;
+
+/// Cuts the bytes of a source into records as they arrive
+struct RecordSource<R> {
+  source: R,
+
+  /// Used to set up the parser
+  file_options: reader::FileOptions,
+
+  /// Bytes read that don't make up a whole record yet
+  buffer: Vec<u8>,
+
+  /// Looks for the end of a record in each new piece of the buffer, so no byte is scanned twice
+  scanner: RecordScanner,
+
+  /// Whether the source has run out
+  eof: bool,
+
+  /// Records parsed but not handed out yet
+  records: VecDeque<Result<StringRecord, CsvError>>,
+}
+
+impl<R: AsyncRead + Unpin> RecordSource<R> {
+  /// Get the next record, reading more of the source when none are ready
+  fn poll_record(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<StringRecord, CsvError>>> {
+    loop {
+      if let Some(record) = self.records.pop_front() {
+        return Poll::Ready(Some(record));
+      }
+      if self.eof {
+        return Poll::Ready(None);
+      }
+
+      let start = self.buffer.len();
+      self.buffer.resize(start + READ_SIZE, 0);
+      let mut buf = ReadBuf::new(&mut self.buffer[start..]);
+      let read = match Pin::new(&mut self.source).poll_read(cx, &mut buf) {
+        Poll::Ready(Ok(())) => buf.filled().len(),
+        Poll::Ready(Err(err)) => {
+          // A half read record can't be trusted, so give up on the rest of the source
+          self.buffer.clear();
+          self.eof = true;
+          return Poll::Ready(Some(Err(err.into())));
+        }
+        Poll::Pending => {
+          self.buffer.truncate(start);
+          return Poll::Pending;
+        }
+      };
+      self.buffer.truncate(start + read);
+
+      if read == 0 {
+        self.eof = true;
+        let rest = std::mem::take(&mut self.buffer);
+        self.parse(&rest);
+      } else if let Some(end) = self
+        .scanner
+        .scan(&self.buffer[start..])
+        .map(|end| start + end)
+      {
+        // The rest of the buffer has been scanned already, leaving the scanner's state at its end
+        let rest = self.buffer.split_off(end);
+        let chunk = std::mem::replace(&mut self.buffer, rest);
+        self.parse(&chunk);
+      }
+    }
+  }
+
+  /// Parse a run of whole records into the queue
+  fn parse(&mut self, bytes: &[u8]) {
+    let mut parser = record_parser(&self.file_options, bytes);
+    loop {
+      let mut record = StringRecord::new();
+      match parser.read_record(&mut record) {
+        Ok(false) => break,
+        Ok(true) => self.records.push_back(Ok(record)),
+        Err(err) => self.records.push_back(Err(err)),
+      }
+    }
+  }
+}
+
+/// A stream of rows read from CSV text as it arrives
+pub struct AsyncCsvReader<R> {
+  records: RecordSource<R>,
+
+  /// Turns the records into rows
+  decoder: Decoder,
+
+  /// A counter pointing to the last line red
+  current_line: i64,
+}
+
+impl<R> std::fmt::Debug for AsyncCsvReader<R> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("AsyncCsvReader")
+      .field("source", &self.decoder.source())
+      .field("current_line", &self.current_line)
+      .finish()
+  }
+}
+
+impl<R: AsyncRead + Unpin> AsyncCsvReader<R> {
+  /// Create a new reader, reading the header line if the file has one
+  ///
+  /// The name is used in error messages, and names the template made from the headers when there
+  /// isn't one. Without a manifest to go on, the layout version comes from the options or is
+  /// detected from the headers.
+  pub async fn new(
+    source: R,
+    name: &str,
+    template: Option<Arc<RowTemplate>>,
+    opts: Option<reader::Options>,
+  ) -> Result<AsyncCsvReader<R>> {
+    let options = opts.unwrap_or_default();
+    let mut records = RecordSource {
+      source,
+      file_options: options.file_options.clone(),
+      buffer: Vec::new(),
+      scanner: RecordScanner::new(&options.file_options),
+      eof: false,
+      records: VecDeque::new(),
+    };
+
+    let headers = match options.file_options.has_headers {
+      false => None,
+      true => match futures::future::poll_fn(|cx| records.poll_record(cx)).await {
+        Some(headers) => Some(
+          err_into!(headers, "Could not read the headers of {}", name)?
+            .iter()
+            .map(|x| x.to_owned())
+            .collect(),
+        ),
+        None => {
+          return Err(err!(
+            EmptyWorksheet,
+            "'{}' doesn't have a header line",
+            name
+          ))
+        }
+      },
+    };
+    let decoder = Decoder::new(name, name, headers, template, &options, options.version)?;
+
+    Ok(AsyncCsvReader {
+      records,
+      decoder,
+      current_line: 0,
+    })
+  }
+}
+
+/// Read the rows in the order of the source, checking the template's keys as they go
+impl<R: AsyncRead + Unpin> Stream for AsyncCsvReader<R> {
+  type Item = Result<Row>;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    let this = self.get_mut();
+    loop {
+      let record = match ready!(this.records.poll_record(cx)) {
+        Some(record) => record,
+        None => return Poll::Ready(None),
+      };
+      this.current_line += 1;
+
+      let record = match err_into!(
+        record,
+        "Error reading record {} from {}",
+        this.current_line,
+        this.decoder.source()
+      ) {
+        Ok(record) => record,
+        Err(err) => return Poll::Ready(Some(Err(err))),
+      };
+
+      if record.len() != this.decoder.width() {
+        return Poll::Ready(Some(Err(err!(
+          ParsingError,
+          "Record {} from {} has {} fields, but the headers have {}",
+          this.current_line,
+          this.decoder.source(),
+          record.len(),
+          this.decoder.width()
+        ))));
+      }
+      if !this.decoder.passes(&record) {
+        continue;
+      }
+
+      let decoded = this.decoder.decode(&record);
+      return Poll::Ready(Some(this.decoder.finish(decoded, this.current_line)));
+    }
+  }
+}
+
+/// A sink writing rows as CSV text
+pub struct AsyncCsvWriter<W> {
+  sink: W,
+
+  /// The name of the destination, for error messages
+  name: String,
+
+  /// Configuration settings for the writer
+  options: writer::Options,
+
+  /// The columns written, in order
+  headers: Vec<String>,
+
+  /// Encodes the records into memory, for writing out when the sink is ready
+  writer: Writer<Vec<u8>>,
+
+  /// Encoded bytes taken from the writer that the sink hasn't taken yet
+  pending: Vec<u8>,
+
+  /// A counter pointing to the last line written
+  current_line: i64,
+
+  /// Defines the columns and how their values are spelled
+  template: Arc<RowTemplate>,
+}
+
+impl<W> std::fmt::Debug for AsyncCsvWriter<W> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("AsyncCsvWriter")
+      .field("name", &self.name)
+      .field("headers", &self.headers)
+      .field("options", &self.options)
+      .field("current_line", &self.current_line)
+      .finish()
+  }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncCsvWriter<W> {
+  /// Create a new writer, with the header line waiting to go out with the first rows
+  pub fn new(
+    sink: W,
+    name: &str,
+    template: Arc<RowTemplate>,
+    opts: Option<writer::Options>,
+  ) -> Result<AsyncCsvWriter<W>> {
+    let options = opts.unwrap_or_default();
+    let headers = writer::headers(&template, &options)?;
+
+    let mut writer = writer::builder(&options.file_options).from_writer(Vec::new());
+    if options.file_options.has_headers {
+      err_into!(
+        writer.write_record(&headers),
+        "Could not write the headers to '{}'",
+        name
+      )?;
+    }
+
+    Ok(AsyncCsvWriter {
+      sink,
+      name: name.to_string(),
+      options,
+      headers,
+      writer,
+      pending: Vec::new(),
+      current_line: 0,
+      template,
+    })
+  }
+
+  /// Serialize the item into a row of the template and send it
+  ///
+  /// Like send on a sink, this waits until the row has been written out.
+  pub async fn write<T: Serialize>(&mut self, item: &T) -> Result<()> {
+    let row = self.template.serialize(item)?;
+    self.send(row).await
+  }
+
+  /// Give back the destination. Close the writer first, or the last rows won't have been written.
+  pub fn into_inner(self) -> W {
+    self.sink
+  }
+
+  /// The number of encoded bytes not written out yet
+  fn buffered(&self) -> usize {
+    self.pending.len() + self.writer.get_ref().len()
+  }
+
+  /// Write out every encoded record, keeping whatever the sink hasn't taken for the next call
+  fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+    loop {
+      if self.pending.is_empty() {
+        // The csv writer only hands over its bytes when it is done, so it's swapped for a new one
+        let fresh = writer::builder(&self.options.file_options).from_writer(Vec::new());
+        let encoded = std::mem::replace(&mut self.writer, fresh);
+        self.pending = err_into!(
+          encoded.into_inner().map_err(|err| err.into_error()),
+          "Could not encode the rows for '{}'",
+          self.name
+        )?;
+        if self.pending.is_empty() {
+          return Poll::Ready(Ok(()));
+        }
+      }
+
+      let written = match ready!(Pin::new(&mut self.sink).poll_write(cx, &self.pending)) {
+        Ok(0) => {
+          return Poll::Ready(Err(err!(
+            FileReadOnly,
+            "'{}' stopped taking rows",
+            self.name
+          )))
+        }
+        Ok(written) => written,
+        Err(err) => return Poll::Ready(err_into!(Err(err), "Could not write to '{}'", self.name)),
+      };
+      self.pending.drain(..written);
+    }
+  }
+}
+
+impl<W: AsyncWrite + Unpin> Sink<Row> for AsyncCsvWriter<W> {
+  type Error = SubparError;
+
+  fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+    let this = self.get_mut();
+    if this.buffered() >= WRITE_SIZE {
+      ready!(this.poll_write_buffer(cx))?;
+    }
+    Poll::Ready(Ok(()))
+  }
+
+  fn start_send(self: Pin<&mut Self>, row: Row) -> Result<()> {
+    let this = self.get_mut();
+    this.current_line += 1;
+
+    let record = writer::encode_row(&this.template, &this.headers, &this.options, &row)?;
+    err_into!(
+      this.writer.write_record(&record),
+      "Could not write line {} to '{}'",
+      this.current_line,
+      this.name
+    )?;
+    // Move the record out of the csv writer's own buffer, so it's counted by poll_ready
+    err_into!(
+      this.writer.flush(),
+      "Could not encode line {}",
+      this.current_line
+    )
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+    let this = self.get_mut();
+    ready!(this.poll_write_buffer(cx))?;
+    Poll::Ready(err_into!(
+      ready!(Pin::new(&mut this.sink).poll_flush(cx)),
+      "Could not flush '{}'",
+      this.name
+    ))
+  }
+
+  fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+    ready!(self.as_mut().poll_flush(cx))?;
+    let this = self.get_mut();
+    Poll::Ready(err_into!(
+      ready!(Pin::new(&mut this.sink).poll_shutdown(cx)),
+      "Could not close '{}'",
+      this.name
+    ))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::StreamExt;
+  use serde_json::Value as JsonValue;
+
+  /// Hands out a few bytes at a time, and makes the reader wait in between
+  struct Trickle {
+    bytes: Vec<u8>,
+    offset: usize,
+    size: usize,
+    waited: bool,
+  }
+
+  impl AsyncRead for Trickle {
+    fn poll_read(
+      self: Pin<&mut Self>,
+      cx: &mut Context<'_>,
+      buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+      let this = self.get_mut();
+      this.waited = !this.waited;
+      if this.waited {
+        cx.waker().wake_by_ref();
+        return Poll::Pending;
+      }
+      let end = (this.offset + this.size)
+        .min(this.bytes.len())
+        .min(this.offset + buf.remaining());
+      buf.put_slice(&this.bytes[this.offset..end]);
+      this.offset = end;
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  /// The cells of each row, for comparing readers
+  fn cells(rows: Vec<Result<Row>>) -> Vec<(JsonValue, JsonValue)> {
+    rows
+      .into_iter()
+      .map(|row| {
+        let row = row.unwrap();
+        (row.get_cell("name").unwrap(), row.get_cell("note").unwrap())
+      })
+      .collect()
+  }
+
+  #[test]
+  fn records_split_across_pieces() {
+    let text = "name,note\n\
+      12\" pipe,plain\n\
+      elbow,\"bent, \"\"45\"\"\nsecond line\"\n\
+      tee,\"crlf\r\ninside\"\r\n\
+      6\" cap,last";
+    let path = helpers::test_dir().join("pipes.csv");
+    std::fs::write(&path, text).unwrap();
+    let sequential = CsvReader::new(Accessor::Csv(path), None, None).unwrap();
+    let expected = cells(sequential.collect());
+    assert_eq!(expected.len(), 4);
+    assert_eq!(expected[0].0, JsonValue::from("12\" pipe"));
+
+    for size in [1, 2, 5, 13] {
+      let source = Trickle {
+        bytes: text.as_bytes().to_vec(),
+        offset: 0,
+        size,
+        waited: false,
+      };
+      let rows = futures::executor::block_on(async {
+        let reader = AsyncCsvReader::new(source, "pipes", None, None)
+          .await
+          .unwrap();
+        reader.collect::<Vec<_>>().await
+      });
+      assert_eq!(cells(rows), expected, "pieces of {} bytes", size);
+    }
+  }
+}
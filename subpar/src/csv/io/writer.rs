@@ -1,3 +1,190 @@
 //! Write to a CSV file
 //!
 //! This wraps the csv::Writer into the common subpar model
+
+pub use crate::local::*;
+
+use super::reader::FileOptions;
+
+pub use ::csv::{Writer, WriterBuilder};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::fs::File;
+pub use std::path::PathBuf;
+
+#[derive(Debug, Default)]
+pub struct Options {
+  /// writer specific options
+  pub file_options: FileOptions,
+
+  /// The order of the columns written. Defaults to the template's columns sorted by name.
+  pub headers: Option<Vec<String>>,
+
+  /// Written for every missing value, instead of each column's null output
+  pub null_value: Option<String>,
+}
+
+/// An open file that rows are written to, one line at a time
+pub struct CsvWriter {
+  /// The location of the file on the filesystem
+  path: PathBuf,
+
+  /// Configuration settings for the writer
+  options: Options,
+
+  /// The columns written, in order
+  headers: Vec<String>,
+
+  /// The underlying CSV writer
+  writer: Writer<File>,
+
+  /// A counter pointing to the last line written
+  current_line: i64,
+
+  /// Defines the columns and how their values are spelled
+  template: Arc<RowTemplate>,
+}
+
+impl std::fmt::Debug for CsvWriter {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("CsvWriter")
+      .field("path", &self.path)
+      .field("headers", &self.headers)
+      .field("options", &self.options)
+      .field("current_line", &self.current_line)
+      .finish()
+  }
+}
+
+impl std::fmt::Display for CsvWriter {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:#?}", self)
+  }
+}
+
+impl CsvWriter {
+  /// Create a new writer, replacing any existing file and writing the header line
+  pub fn new(
+    accessor: Accessor,
+    template: Arc<RowTemplate>,
+    opts: Option<Options>,
+  ) -> Result<CsvWriter> {
+    let options = opts.unwrap_or_default();
+
+    let canon = &accessor.canonicalize(true)?;
+    let Accessor::Csv(path) = canon;
+    let mut writer = err_into!(
+      builder(&options.file_options).from_path(path.as_path()),
+      "Could not open '{}' for writing",
+      path.to_string_lossy()
+    )?;
+
+    let headers = headers(&template, &options)?;
+
+    if options.file_options.has_headers {
+      err_into!(
+        writer.write_record(&headers),
+        "Could not write the headers to '{}'",
+        path.to_string_lossy()
+      )?;
+    }
+
+    Ok(CsvWriter {
+      path: path.clone(),
+      options,
+      headers,
+      writer,
+      current_line: 0,
+      template,
+    })
+  }
+
+  /// Write the row's cells as the next line of the file
+  pub fn write_row(&mut self, row: &Row) -> Result<()> {
+    self.current_line += 1;
+
+    let record = encode_row(&self.template, &self.headers, &self.options, row)?;
+    err_into!(
+      self.writer.write_record(&record),
+      "Could not write line {} to '{}'",
+      self.current_line,
+      self.path.to_string_lossy()
+    )
+  }
+
+  /// Serialize the item into a row of the template and write it as the next line
+  pub fn write<T: Serialize>(&mut self, item: &T) -> Result<()> {
+    let row = self.template.serialize(item)?;
+    self.write_row(&row)
+  }
+
+  /// Write the items to a new file, using their type's template
+  pub fn dump<T: SubparRow + Serialize>(
+    path: &str,
+    items: &[T],
+    opts: Option<Options>,
+  ) -> Result<()> {
+    let template = Arc::new(T::get_template());
+    let mut writer = CsvWriter::new(Accessor::new_csv(path), template, opts)?;
+    for item in items {
+      writer.write(item)?;
+    }
+    writer
+      .flush()
+      .context(format!("Failed to dump CSV file at '{}'", path))
+  }
+
+  /// Push any buffered lines out to the file
+  pub fn flush(&mut self) -> Result<()> {
+    err_into!(
+      self.writer.flush(),
+      "Could not flush '{}'",
+      self.path.to_string_lossy()
+    )
+  }
+}
+
+/// A CSV writer set up with the file options
+pub(crate) fn builder(options: &FileOptions) -> WriterBuilder {
+  let mut builder = WriterBuilder::new();
+  builder
+    .delimiter(options.delimiter)
+    .quote(options.quote)
+    .double_quote(options.double_quotes)
+    .has_headers(options.has_headers);
+  builder
+}
+
+/// The columns to write, in order
+pub(crate) fn headers(template: &RowTemplate, options: &Options) -> Result<Vec<String>> {
+  match &options.headers {
+    Some(headers) => Ok(headers.clone()),
+    None => {
+      let mut headers = template.get_headers()?;
+      headers.sort();
+      Ok(headers)
+    }
+  }
+}
+
+/// Spell out the row's cells in the order of the headers
+pub(crate) fn encode_row(
+  template: &RowTemplate,
+  headers: &[String],
+  options: &Options,
+  row: &Row,
+) -> Result<Vec<String>> {
+  let cells: HashMap<String, JsonValue> = row.flatten().into_iter().collect();
+  let mut record = Vec::with_capacity(headers.len());
+  for name in headers.iter() {
+    let value = cells.get(name).unwrap_or(&JsonValue::Null);
+    let text = match (value, &options.null_value) {
+      (JsonValue::Null, Some(null)) => null.clone(),
+      (value, _) => template
+        .encode(name, value)?
+        .to_text(template.get_options(name)),
+    };
+    record.push(text);
+  }
+  Ok(record)
+}
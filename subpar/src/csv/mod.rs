@@ -1,8 +1,8 @@
 //! Work with CSV files
 
-// pub mod instance;
-// pub use instance::CsvWorkbook;
+pub mod instance;
+pub use instance::CsvWorkbook;
 
 // Read/Write implementations
 pub mod io;
-pub use io::CsvReader;
+pub use io::{CsvReader, CsvWriter};
@@ -46,6 +46,21 @@ impl std::fmt::Display for SubparError {
   }
 }
 
+impl std::error::Error for SubparError {}
+
+/// Lets subpar's own serializers and deserializers report through serde
+impl serde::de::Error for SubparError {
+  fn custom<T: std::fmt::Display>(msg: T) -> SubparError {
+    SubparError::new(Kind::ConversionError).comment(msg.to_string())
+  }
+}
+
+impl serde::ser::Error for SubparError {
+  fn custom<T: std::fmt::Display>(msg: T) -> SubparError {
+    SubparError::new(Kind::ConversionError).comment(msg.to_string())
+  }
+}
+
 /// The full set of exceptions that can be raised at any step in this process
 ///
 /// This will be used as the "source" of the SubparError error
@@ -105,6 +120,9 @@ pub enum Kind {
   #[error("A required value was not set")]
   NullValue,
 
+  #[error("A value did not match its column's string format")]
+  InvalidFormat,
+
   #[error("FloatParseError")]
   FloatParseError,
   #[error("ReadOnly")]
@@ -121,6 +139,8 @@ pub enum Kind {
   SheetsError,
   #[error("WorkbookMismatch")]
   WorkbookMismatch,
+  #[error("A row template cannot safely be used with a sheet")]
+  IncompatibleTemplate,
   #[error("ParsingError")]
   ParsingError,
   #[error("There a problem with locking an object for read/write due to an uncaught error")]
@@ -137,6 +157,18 @@ pub enum Kind {
   #[error("Error converting a string to an integer")]
   ParseIntError(#[from] std::num::ParseIntError),
 
+  #[error("Error converting a string to a date or time")]
+  DateParseError(#[from] chrono::ParseError),
+
+  #[error("Error converting a string to a URL")]
+  UrlParseError(#[from] url::ParseError),
+
+  #[error("Error converting a string to a uuid")]
+  UuidError(#[from] uuid::Error),
+
+  #[error("A regular expression could not be compiled")]
+  RegexError(#[from] regex::Error),
+
   #[error("IO Error")]
   Io(#[from] std::io::Error),
 }
@@ -161,6 +193,11 @@ pub trait Comment<T, E> {
   fn context<C>(self, ctx: C) -> Result<T, SubparError>
   where
     C: std::fmt::Display + std::fmt::Debug + Sync + Send + 'static;
+  /// Like context, but only builds the message when there is an error. Use this in hot loops.
+  fn with_context<C, F>(self, ctx: F) -> Result<T, SubparError>
+  where
+    C: std::fmt::Display + std::fmt::Debug + Sync + Send + 'static,
+    F: FnOnce() -> C;
 }
 
 impl<T, E> Comment<T, E> for Result<T, E>
@@ -196,6 +233,17 @@ where
       }
     }
   }
+
+  fn with_context<C, F>(self, ctx: F) -> Result<T, SubparError>
+  where
+    C: std::fmt::Display + std::fmt::Debug + Sync + Send + 'static,
+    F: FnOnce() -> C,
+  {
+    match self {
+      Ok(x) => Ok(x),
+      Err(err) => Err(err).context(ctx()),
+    }
+  }
 }
 
 /// Simple macro to create an FHL error
@@ -47,55 +47,84 @@ pub fn to_sheet_name(path: &Path) -> Result<&str> {
   })
 }
 
-/*
+/// The Levenshtein distance between two strings, compared by character
+///
+/// Used to suggest the intended name when a header or key is misspelled
+pub fn edit_distance(left: &str, right: &str) -> usize {
+  let right: Vec<char> = right.chars().collect();
+  let mut previous: Vec<usize> = (0..=right.len()).collect();
+  let mut current = vec![0; right.len() + 1];
+
+  for (i, l_char) in left.chars().enumerate() {
+    current[0] = i + 1;
+    for (j, r_char) in right.iter().enumerate() {
+      let cost = if l_char == *r_char { 0 } else { 1 };
+      current[j + 1] = (previous[j] + cost)
+        .min(previous[j + 1] + 1)
+        .min(current[j] + 1);
+    }
+    std::mem::swap(&mut previous, &mut current);
+  }
+  previous[right.len()]
+}
+
 /// Check a file extension matches in a case insensitive fashion
 ///
 /// This can potentially fail if the extension cannot be converted to ascii. Erroring is more
 /// useful than calling it "false"
-pub fn cmp_extension(path: &PathBuf, extension: &str) -> Result<bool> {
+pub fn cmp_extension(path: &Path, extension: &str) -> Result<bool> {
   path.extension().map_or(Ok(false), |x| {
     x.to_ascii_lowercase()
       .to_str()
       .map(|ext| ext == extension)
-      .ok_or(SubparError::ConversionError)
-      .ctx("'csv::Location::new' could not convert extension to ascii")
+      .ok_or_else(|| {
+        err!(
+          ConversionError,
+          "Could not convert the extension of '{}' to ascii",
+          path.to_string_lossy()
+        )
+      })
   })
 }
 
-
 /// Find all the files in a directory with a CSV extension
-pub fn list_csv_files(path: &PathBuf) -> Result<Vec<PathBuf>> {
+pub fn list_csv_files(path: &Path) -> Result<Vec<PathBuf>> {
   if !path.is_dir() {
-    Err(SubparError::InvalidPath).ctx("The path {:?} is not a directory")?
+    return Err(err!(
+      InvalidPath,
+      "The path {} is not a directory",
+      path.to_string_lossy()
+    ));
   };
 
   let mut result = vec![];
-  for entry in path.read_dir()? {
-    match entry {
-      Ok(e) => match cmp_extension(&e.path(), "csv")? {
-        true => result.push(e.path().clone()),
-        false => (),
-      },
-      Err(_) => {
-        entry.ctx("error in list_csv_files")?;
-        ()
-      }
+  for entry in err_into!(
+    path.read_dir(),
+    "Could not list '{}'",
+    path.to_string_lossy()
+  )? {
+    let entry = err_into!(entry, "Could not list '{}'", path.to_string_lossy())?;
+    if entry.path().is_file() && cmp_extension(&entry.path(), "csv")? {
+      result.push(entry.path());
     }
   }
+  result.sort();
   Ok(result)
 }
 
 /// Convert a Path/PathBuf to a string, failing if it can't
-pub fn path_to_str(path: &PathBuf) -> Result<&str> {
-  path
-    .to_str()
-    .clone()
-    .ok_or(SubparError::ConversionError)
-    .ctx("csv::helpers::path_to_str could not convert file name to ascii")
+pub fn path_to_str(path: &Path) -> Result<&str> {
+  path.to_str().ok_or_else(|| {
+    err!(
+      ConversionError,
+      "Could not convert the path '{}' to a string",
+      path.to_string_lossy()
+    )
+  })
 }
 
 /// Use a path to create a unique id
-pub fn path_to_id(path: &PathBuf) -> Result<Uuid> {
+pub fn path_to_id(path: &Path) -> Result<Uuid> {
   Ok(Uuid::new_v5(
     &Uuid::NAMESPACE_OID,
     ["csv", "|", path_to_str(path)?]
@@ -107,4 +136,61 @@ pub fn path_to_id(path: &PathBuf) -> Result<Uuid> {
       .as_bytes(),
   ))
 }
-*/
+
+/// An empty directory for a test's files, under the system's temp directory
+#[cfg(test)]
+pub(crate) fn test_dir() -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("subpar-{}", Uuid::new_v4()));
+  std::fs::create_dir_all(&dir).unwrap();
+  dir
+}
+
+/// Define a struct for tests and make it a SubparRow, with its template from its JSON schema
+#[cfg(test)]
+macro_rules! test_row {
+  ($name:ident { $($field:ident: $ty:ty),* $(,)? }) => {
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, schemars::JsonSchema)]
+    struct $name {
+      $($field: $ty),*
+    }
+
+    impl TryFrom<Row> for $name {
+      type Error = SubparError;
+
+      fn try_from(row: Row) -> Result<$name> {
+        row.deserialize()
+      }
+    }
+
+    impl TryFrom<$name> for Row {
+      type Error = SubparError;
+
+      fn try_from(item: $name) -> Result<Row> {
+        Row::from_item(&item)
+      }
+    }
+
+    impl SubparRow for $name {
+      fn get_template() -> RowTemplate {
+        schemars::schema_for!($name).into()
+      }
+    }
+  };
+}
+#[cfg(test)]
+pub(crate) use test_row;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn edit_distances() {
+    assert_eq!(edit_distance("", ""), 0);
+    assert_eq!(edit_distance("name", "name"), 0);
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(edit_distance("email", "emial"), 2);
+    assert_eq!(edit_distance("café", "cafe"), 1);
+  }
+}
@@ -42,8 +42,7 @@ mod local {
 
   // Make all the types safe to use
   pub use std::borrow::{Borrow, BorrowMut};
-  pub use std::cell::RefCell;
-  pub use std::rc::Rc;
+  pub use std::sync::{Arc, Mutex, RwLock};
 
   // Everything should be identified uniquely
   pub use uuid::Uuid;
@@ -74,20 +73,45 @@ pub mod prelude {
       self,
       accessor::Accessor,
       cell::{Cell, CellValue},
-      //   instance::{Mode, SubparWorkbook},
+      instance::{Mode, SubparWorkbook},
       //   messages::{Action, Event},
-      //   sheet::{Sheet, SheetAccessor, SheetTemplate, SubparSheet},
-      //   workbook::Workbook,
       row::{Row, RowTemplate, SubparRow},
+      sheet::{Sheet, SheetAccessor, SheetTemplate, SubparSheet},
+      workbook::Workbook,
     },
     errors::SubparError,
   };
 
   #[cfg(feature = "csv_tables")]
-  pub use crate::csv::{self, io::CsvReader};
+  pub use crate::csv::{self, io::CsvReader, io::CsvWriter};
 
-  // pub(crate) use base::state::State;
+  #[cfg(feature = "decimal")]
+  pub use rust_decimal::Decimal;
+
+  pub(crate) use base::state::State;
 
   // #[cfg(feature = "cartography")]
   // pub use {crate::cartograph, cartograph::ServerPoI};
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn is_send_sync<T: Send + Sync>() {}
+
+  /// The core types get shared between threads, such as by async web services
+  #[test]
+  fn shared_types_are_send_sync() {
+    is_send_sync::<base::row::RowTemplate>();
+    is_send_sync::<base::row::Row>();
+    is_send_sync::<errors::SubparError>();
+    is_send_sync::<base::workbook::Workbook>();
+
+    #[cfg(feature = "csv_tables")]
+    {
+      is_send_sync::<csv::CsvReader>();
+      is_send_sync::<csv::CsvWriter>();
+    }
+  }
+}
@@ -20,7 +20,7 @@ pub enum SubparActions {
 pub enum SubparEvents {
   OpenedFile(&str),
 
-  ReadRow(Rc<Row>),
+  ReadRow(Arc<Row>),
 }
 
 // ----------------------------- The Server  -----------------------------